@@ -0,0 +1,14 @@
+#!/bin/sh
+# Builds the browser client into web/, ready to be served as static files, e.g. with
+# `python3 -m http.server --directory web`
+set -e
+
+cargo build --release --target wasm32-unknown-unknown --bin guards
+cp target/wasm32-unknown-unknown/release/guards.wasm web/
+
+# macroquad's half of the glue, taken from the exact version we built against
+MACROQUAD_DIR=$(cargo metadata --format-version 1 \
+    | grep -o '"manifest_path":"[^"]*/macroquad-[0-9.]*/Cargo.toml"' \
+    | head -n 1 \
+    | sed -e 's/^"manifest_path":"//' -e 's/\/Cargo.toml"$//')
+cp "$MACROQUAD_DIR/js/mq_js_bundle.js" web/
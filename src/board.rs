@@ -1,8 +1,8 @@
 use std::fmt::{Write, Display, Formatter, Error};
 use std::collections::{HashMap, HashSet};
-use std::fs;
 
 use crate::game::Team;
+use serde::{Deserialize, Serialize};
 
 #[derive(PartialEq, Clone, Copy)]
 #[allow(dead_code)] // this is not used by the server
@@ -13,8 +13,8 @@ pub enum SquareOccupant {
     Guard(Team),
     // citizens are player-controlled pieces
     Citizen(Team),
-    // the magistrate is controlled by the player who doesn't control the guards, and
-    // cannot be used to kill but can be used to block pieces in.
+    // the magistrate is controlled by the player who doesn't control the guards, and acts as
+    // a neutral blocking piece (see RuleSet::magistrate_can_kill)
     Magistrate(Team),
 }
 
@@ -52,7 +52,7 @@ impl Square {
     }
 }
 
-#[derive(PartialEq, Eq, Hash, Copy, Clone, Debug)]
+#[derive(PartialEq, Eq, Hash, Copy, Clone, Debug, Serialize, Deserialize)]
 pub struct BoardSquareCoords(pub u32, pub u32);
 
 impl Display for BoardSquareCoords {
@@ -82,6 +82,33 @@ impl std::str::FromStr for BoardSquareCoords {
     }
 }
 
+// built in rather than read at runtime, so the game doesn't depend on its working directory
+pub const DEFAULT_LAYOUT: &str = include_str!("../board.txt");
+pub const BOARD_WIDTH: usize = 7;
+pub const BOARD_HEIGHT: usize = 9;
+
+// checks a layout read from a file or the network, returning it tidied up for from_layout
+#[allow(dead_code)]
+pub fn parse_layout(board_txt: &str) -> Result<String, String> {
+    let rows: Vec<&str> = board_txt
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty())
+        .collect();
+    if rows.len() != BOARD_HEIGHT {
+        return Err(format!("Expected {BOARD_HEIGHT} rows, found {}", rows.len()));
+    }
+    for (y, row) in rows.iter().enumerate() {
+        if row.chars().count() != BOARD_WIDTH || !row.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format!(
+                "Row {} should be {BOARD_WIDTH} hex digits, not {row:?}",
+                y + 1
+            ));
+        }
+    }
+    Ok(rows.join("\n"))
+}
+
 #[allow(dead_code)]
 pub struct Board {
     pub squares: HashMap<BoardSquareCoords, Square>,
@@ -90,18 +117,26 @@ pub struct Board {
 }
 
 
+impl Default for Board {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[allow(dead_code)]
 impl Board {
     pub fn new() -> Self {
-        let mut squares = HashMap::new();
-        let board_txt = fs::read_to_string("board.txt").map_err(|err| panic!("Board file missing: {err}")).unwrap();
+        Self::from_layout(DEFAULT_LAYOUT)
+    }
 
+    // one line of hex digits per row, each digit a bitmask of the walls around that square
+    pub fn from_layout(board_txt: &str) -> Self {
+        let mut squares = HashMap::new();
         let board_lines: Vec<&str> = board_txt.lines().collect();
         let mut width: u32 = 0;
         let height: u32 = board_lines.len() as u32;
 
-        for y in 0..board_lines.len() {
-            let line = board_lines[y];
+        for (y, line) in board_lines.iter().enumerate() {
             if line.len() > width as usize {
                 width = line.len() as u32;
             }
@@ -1,36 +1,86 @@
-use std::env::current_exe;
-use std::fmt::Write;
-use std::io::{BufRead, Write as IOWrite};
-use std::net::TcpStream;
-use std::process::{Child, Command};
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::sync::mpsc::{channel, sync_channel};
-use std::thread::{self, sleep};
-use std::time;
+use std::sync::OnceLock;
+use std::fmt::Write;
+use std::collections::VecDeque;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Instant;
 use tracing::{debug, error, info};
-use tracing_subscriber;
 
 pub mod board;
+mod discovery;
 mod game;
+#[cfg(not(target_arch = "wasm32"))]
+mod host;
 mod menu;
 mod net;
-use crate::board::{BoardSquareCoords, SquareEdge, SquareOccupant};
-use crate::game::{GameState, Team, WinState};
+// only RoomDefaults is needed when there's no hosting
+#[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+mod room;
+mod text_input;
+mod transport;
+use crate::board::{parse_layout, BoardSquareCoords, SquareEdge, SquareOccupant, DEFAULT_LAYOUT};
+use crate::discovery::LanBrowser;
+use crate::game::{EndReason, GameState, RuleSet, Team, TimeControl, WinState};
 use crate::menu::{render_menu, MenuOption, MenuState, MENU_FONT_SIZE};
-use crate::net::PORT;
+use crate::net::{
+    is_valid_server_name, Capability, ClientMessage, OfferAction, ProtocolError, ProtocolMessage,
+    RoomInfo, ServerAddress, MAX_CHAT_LEN, PEER_TIMEOUT_SECS, PING_INTERVAL_SECS, PROTOCOL_VERSION, PORT, SEAT_GRACE_PERIOD_SECS,
+};
+use crate::room::RoomDefaults;
+use crate::text_input::TextInput;
+use crate::transport::{Offline, PendingConnection, Transport};
 
 use macroquad::prelude::{
     clear_background, draw_circle, draw_line, draw_rectangle, draw_rectangle_lines, draw_text,
-    draw_texture, draw_texture_ex, get_frame_time, get_last_key_pressed, is_key_down,
-    is_key_pressed, is_mouse_button_pressed, load_texture, measure_text, mouse_position,
+    draw_texture, draw_texture_ex, get_frame_time, get_time,
+    is_key_pressed, is_mouse_button_pressed, measure_text, mouse_position,
     next_frame, screen_height, screen_width, vec2, Color, Conf, DrawTextureParams, KeyCode,
-    MouseButton, TextDimensions, Texture2D, BEIGE, BLACK, GRAY, PINK, PURPLE, RED, WHITE,
+    MouseButton, Texture2D, BEIGE, BLACK, GRAY, PINK, PURPLE, RED, WHITE,
 };
+use macroquad::rand;
+
+use clap::Parser;
+
+/// Guards! - murder in the forum.
+#[derive(Parser, Debug)]
+#[command(version)]
+struct Args {
+    /// Join the server at host[:port] instead of starting at the main menu; IPv6 addresses go in [brackets]
+    #[arg(long, value_name = "HOST[:PORT]", conflicts_with_all = ["host", "replay"])]
+    connect: Option<ServerAddress>,
+    /// Team to take in online games rather than picking one: purple or white
+    #[arg(long, value_parser = parse_team)]
+    team: Option<Team>,
+    /// Host a multiplayer game with the default rules, untimed
+    #[arg(long, conflicts_with = "replay")]
+    host: bool,
+    /// Run in a window rather than fullscreen
+    #[arg(long)]
+    windowed: bool,
+    /// Play local games on the board layout in this file instead of the built-in one
+    #[arg(long)]
+    board: Option<PathBuf>,
+    /// Step through a recorded game, written as protocol lines (rules, board, move or sync)
+    #[arg(long)]
+    replay: Option<PathBuf>,
+}
+
+fn parse_team(s: &str) -> Result<Team, String> {
+    Team::from_str(s).map_err(|_| format!("expected purple or white, not {s}"))
+}
+
+// parsed once, the first time it's needed, which is when macroquad asks for the window settings
+fn args() -> &'static Args {
+    static ARGS: OnceLock<Args> = OnceLock::new();
+    ARGS.get_or_init(Args::parse)
+}
 
 fn conf() -> Conf {
     Conf {
         window_title: String::from("Guards!"),
-        fullscreen: true,
+        fullscreen: !args().windowed,
         ..Default::default()
     }
 }
@@ -41,45 +91,155 @@ const CITIZEN_SIZE: f32 = 45.0;
 const MAGISTRATE_SIZE: f32 = 47.5;
 const DOT_SIZE: f32 = 5.0;
 const WALL_THICKNESS: f32 = 3.0;
+const CHAT_FONT_SIZE: f32 = 24.0;
+const CHAT_MARGIN: f32 = 16.0;
+// how many chat messages we hang on to
+const CHAT_HISTORY: usize = 50;
+// long enough for any host name and port
+const MAX_ADDRESS_LEN: usize = 260;
+// how often we try to connect to a server we're hosting before giving up on it
+const SERVER_CONNECT_ATTEMPTS: u32 = 6;
+// the most rooms a game hosted from the client can have open, counting the main room
+const HOSTED_MAX_ROOMS: usize = 10;
+// how long a status message stays on screen, and how many can be shown at once
+const STATUS_SECS: f64 = 4.0;
+const STATUS_HISTORY: usize = 3;
+
+// hosting isn't offered in the browser, but the code that does it still has to build there
+#[cfg(target_arch = "wasm32")]
+mod host {
+    use crate::net::Ready;
+    use crate::room::RoomDefaults;
+    use std::net::IpAddr;
+
+    #[allow(dead_code)]
+    pub struct HostOptions {
+        pub bind: IpAddr,
+        pub port: u16,
+        pub websocket_port: u16,
+        pub room_defaults: RoomDefaults,
+        pub max_rooms: usize,
+        pub name: String,
+        pub announce_to: Option<IpAddr>,
+    }
+
+    pub struct Host;
 
-struct ChildGuard(Child);
+    impl Host {
+        pub fn start(_options: HostOptions) -> Result<Self, String> {
+            Err("Games can't be hosted from the browser".to_string())
+        }
 
-impl Drop for ChildGuard {
-    fn drop(&mut self) {
-        match self.0.kill() {
-            Err(e) => println!("Could not kill child process: {e}"),
-            Ok(_) => println!("Child killed successfully"),
+        pub fn ready(&self) -> Ready {
+            unreachable!("no Host can be started in the browser")
         }
     }
 }
 
 struct PlayerState {
     selected_square: Option<BoardSquareCoords>,
+    // a move we've sent that the server hasn't played or turned down yet; no other move can be
+    // made until it has
+    pending_move: Option<(BoardSquareCoords, BoardSquareCoords)>,
     playing_as: Option<Team>,
+    // spectators see the board but can't select or move pieces
+    spectating: bool,
+    // how the last game ended, as told by the server
+    end_reason: Option<EndReason>,
+    // whoever is waiting on an answer to their draw or rematch offer
+    draw_offer: Option<Team>,
+    rematch_offer: Option<Team>,
+    // likewise for proposed rules or a proposed time control, along with what was proposed
+    rules_offer: Option<(Team, RuleSet)>,
+    time_control_offer: Option<(Team, Option<TimeControl>)>,
+    // the rules and time control can only be changed until then
+    first_move_played: bool,
+    // the room's time control, as told by the server
+    time_control: Option<TimeControl>,
+    // None in untimed games
+    clock: Option<ClockState>,
 }
 
 impl PlayerState {
     fn new() -> Self {
         Self {
             selected_square: None,
+            pending_move: None,
             playing_as: None,
+            spectating: false,
+            end_reason: None,
+            draw_offer: None,
+            rematch_offer: None,
+            rules_offer: None,
+            time_control_offer: None,
+            first_move_played: false,
+            time_control: None,
+            clock: None,
+        }
+    }
+
+    // forget anything left over from the last game
+    fn new_game(&mut self) {
+        self.selected_square = None;
+        self.pending_move = None;
+        self.end_reason = None;
+        self.draw_offer = None;
+        self.rematch_offer = None;
+        self.rules_offer = None;
+        self.time_control_offer = None;
+        self.first_move_played = false;
+        self.clock = None;
+    }
+}
+
+// the clocks as last reported by the server, which we count down ourselves until the next report
+struct ClockState {
+    purple_ms: u64,
+    white_ms: u64,
+    running: Option<Team>,
+    // when the report arrived, in seconds since the client started
+    received: f64,
+}
+
+impl ClockState {
+    // seconds left on a player's clock
+    fn remaining(&self, team: Team, now: f64) -> f64 {
+        let reported = match team {
+            Team::Purple => self.purple_ms,
+            _ => self.white_ms,
+        } as f64
+            / 1000.0;
+        if self.running == Some(team) {
+            (reported - (now - self.received)).max(0.0)
+        } else {
+            reported
         }
     }
 }
 
-fn get_num(key: KeyCode) -> Option<u32> {
-    match key {
-        KeyCode::Key0 | KeyCode::Kp0 => Some(0),
-        KeyCode::Key1 | KeyCode::Kp1 => Some(1),
-        KeyCode::Key2 | KeyCode::Kp2 => Some(2),
-        KeyCode::Key3 | KeyCode::Kp3 => Some(3),
-        KeyCode::Key4 | KeyCode::Kp4 => Some(4),
-        KeyCode::Key5 | KeyCode::Kp5 => Some(5),
-        KeyCode::Key6 | KeyCode::Kp6 => Some(6),
-        KeyCode::Key7 | KeyCode::Kp7 => Some(7),
-        KeyCode::Key8 | KeyCode::Kp8 => Some(8),
-        KeyCode::Key9 | KeyCode::Kp9 => Some(9),
-        _ => None,
+fn format_clock(secs: f64) -> String {
+    // tenths of a second matter once time is nearly up
+    if secs < 10.0 {
+        format!("{secs:.1}")
+    } else {
+        let secs = secs.ceil() as u64;
+        format!("{}:{:02}", secs / 60, secs % 60)
+    }
+}
+
+fn render_clocks(clock: &ClockState, x: f32, y: f32) {
+    let now = get_time();
+    let mut x = x;
+    for team in [Team::Purple, Team::White] {
+        let remaining = clock.remaining(team, now);
+        let s = format!("{team}: {}", format_clock(remaining));
+        let color = match clock.running {
+            Some(running) if running == team && remaining < 10.0 => RED,
+            Some(running) if running == team => WHITE,
+            _ => GRAY,
+        };
+        draw_text(&s, x, y, 32.0, color);
+        x += measure_text(&s, None, 32, 1.0).width + 32.0;
     }
 }
 
@@ -92,7 +252,15 @@ fn render_game_state(
     let (screen_width, screen_height) = (screen_width(), screen_height());
     match &game_state.game_over {
         Some(win) => {
-            let rect_height = 100.0;
+            let reason = player_state.end_reason.and_then(|reason| match (reason, win) {
+                (EndReason::Resignation, WinState::PurpleWin) => Some("White resigned"),
+                (EndReason::Resignation, WinState::WhiteWin) => Some("Purple resigned"),
+                (EndReason::Agreement, _) => Some("Draw agreed"),
+                (EndReason::Timeout, WinState::PurpleWin) => Some("White ran out of time"),
+                (EndReason::Timeout, WinState::WhiteWin) => Some("Purple ran out of time"),
+                _ => None,
+            });
+            let rect_height = if reason.is_some() { 132.0 } else { 100.0 };
             let rect_width = 250.0;
             let rect_x = (screen_width - rect_width) / 2.0;
             let rect_y = (screen_height - rect_height) / 2.0;
@@ -138,7 +306,18 @@ fn render_game_state(
                     );
                 }
             }
-            if is_mouse_button_pressed(MouseButton::Left) {
+            if let Some(reason) = reason {
+                let size = measure_text(reason, None, 24, 1.0);
+                draw_text(
+                    reason,
+                    rect_x + (rect_width - size.width) / 2.0,
+                    rect_y + (text_height * 3.0),
+                    24.0,
+                    WHITE,
+                );
+            }
+            // online, the server decides when the next game starts (see render_game_controls)
+            if !connected && is_mouse_button_pressed(MouseButton::Left) {
                 game_state.reset();
             }
             None
@@ -148,8 +327,14 @@ fn render_game_state(
             let _ = write!(s, "Current turn: {}", game_state.get_turn());
             let (mouse_x, mouse_y) = mouse_pos;
             draw_text(s.as_str(), 0.0, 32.0, 32.0, WHITE);
-            if connected {
-                let s = format!("Playing as: {}", player_state.playing_as.unwrap());
+            if let Some(clock) = &player_state.clock {
+                let width = measure_text(s.as_str(), None, 32, 1.0).width;
+                render_clocks(clock, width + 48.0, 32.0);
+            }
+            if player_state.spectating {
+                draw_text("Spectating", 0.0, 64.0, 32.0, WHITE);
+            } else if let Some(team) = player_state.playing_as.filter(|_| connected) {
+                let s = format!("Playing as: {team}");
                 draw_text(s.as_str(), 0.0, 64.0, 32.0, WHITE);
             }
 
@@ -187,7 +372,10 @@ fn render_game_state(
                         SQUARE_SIZE,
                         Color::new(0.5, 0.5, 0.5, 0.5),
                     );
-                    if is_mouse_button_pressed(MouseButton::Left) {
+                    if !player_state.spectating
+                        && player_state.pending_move.is_none()
+                        && is_mouse_button_pressed(MouseButton::Left)
+                    {
                         if let Some(player_sq) = player_state.selected_square {
                             if game_state.valid_move(player_sq, *coord) {
                                 draw_rectangle(
@@ -209,7 +397,7 @@ fn render_game_state(
                             }
                             player_state.selected_square = None;
                         } else {
-                            player_state.selected_square = Some(coord.clone());
+                            player_state.selected_square = Some(*coord);
                             draw_rectangle(
                                 x,
                                 y,
@@ -236,6 +424,18 @@ fn render_game_state(
                     }
                 }
 
+                if let Some((from, to)) = player_state.pending_move {
+                    if from == *coord || to == *coord {
+                        draw_rectangle(
+                            x,
+                            y,
+                            SQUARE_SIZE,
+                            SQUARE_SIZE,
+                            Color::new(0.5, 0.7, 0.5, 1.0),
+                        );
+                    }
+                }
+
                 // Draw walls with increased thickness
                 if square.wall.contains(&SquareEdge::North) {
                     draw_line(x, y, x + SQUARE_SIZE, y, WALL_THICKNESS, BLACK);
@@ -331,11 +531,135 @@ fn render_game_state(
     }
 }
 
+// the preset after the current one, so that a single button can cycle through them all
+fn next_preset<T: PartialEq + Copy>(presets: &[(&str, T)], current: T) -> T {
+    let next = presets.iter().position(|(_, preset)| *preset == current).map_or(0, |i| i + 1);
+    presets[next % presets.len()].1
+}
+
+// minutes+seconds per move like the presets, rather than the seconds the protocol uses; a base
+// that isn't whole minutes spells out its units instead
+fn time_control_name(time_control: Option<TimeControl>) -> String {
+    if let Some((name, _)) = TimeControl::PRESETS.iter().find(|(_, preset)| *preset == time_control) {
+        return name.to_string();
+    }
+    let Some(TimeControl { base, increment }) = time_control else {
+        return "Untimed".to_string();
+    };
+    let (base, increment) = (base.as_secs(), increment.as_secs());
+    if base % 60 == 0 {
+        format!("{}+{increment}", base / 60)
+    } else {
+        format!("{base}s+{increment}s")
+    }
+}
+
+// resign, draw and rematch buttons down the top right of the screen, for players in online games;
+// until the first move there are also buttons for proposing other rules and time controls, which
+// are all there is while we're still waiting for the game to start
+fn render_game_controls(
+    game_state: &GameState,
+    player_state: &PlayerState,
+    playing: bool,
+    clocks: bool,
+) -> Option<ClientMessage> {
+    let team = player_state.playing_as?;
+    let opponent = team.opposite();
+    let (mouse_x, mouse_y) = mouse_position();
+    let mut buttons: Vec<(String, Option<ClientMessage>)> = Vec::new();
+    let mut button = |label: &str, msg| buttons.push((label.to_string(), msg));
+    if playing && game_state.game_over.is_none() {
+        button("Resign", Some(ClientMessage::Resign));
+        match player_state.draw_offer {
+            Some(offered_by) if offered_by == opponent => {
+                button("Accept draw", Some(ClientMessage::Draw(OfferAction::Accept)));
+                button("Decline draw", Some(ClientMessage::Draw(OfferAction::Decline)));
+            }
+            Some(_) => button("Draw offered", None),
+            None => button("Offer draw", Some(ClientMessage::Draw(OfferAction::Offer))),
+        }
+    } else if playing {
+        match player_state.rematch_offer {
+            Some(offered_by) if offered_by == opponent => {
+                button("Accept rematch", Some(ClientMessage::Rematch(OfferAction::Accept)));
+                button("Decline rematch", Some(ClientMessage::Rematch(OfferAction::Decline)));
+            }
+            Some(_) => button("Rematch offered", None),
+            None => button("Rematch", Some(ClientMessage::Rematch(OfferAction::Offer))),
+        }
+    }
+    if !player_state.first_move_played && game_state.game_over.is_none() {
+        match player_state.rules_offer {
+            Some((offered_by, rules)) if offered_by == opponent => {
+                let answer = |action| Some(ClientMessage::Rules(action, rules));
+                button(&format!("Accept {rules} rules"), answer(OfferAction::Accept));
+                button(&format!("Decline {rules} rules"), answer(OfferAction::Decline));
+            }
+            Some((_, rules)) => button(&format!("Proposed {rules} rules"), None),
+            None => {
+                let rules = next_preset(&RuleSet::PRESETS, *game_state.get_rules());
+                let msg = ClientMessage::Rules(OfferAction::Offer, rules);
+                button(&format!("Propose {rules} rules"), Some(msg));
+            }
+        }
+        match player_state.time_control_offer {
+            // a server without clocks has no time control to change
+            _ if !clocks => {}
+            Some((offered_by, time_control)) if offered_by == opponent => {
+                let name = time_control_name(time_control);
+                let answer = |action| Some(ClientMessage::TimeControl(action, time_control));
+                button(&format!("Accept {name}"), answer(OfferAction::Accept));
+                button(&format!("Decline {name}"), answer(OfferAction::Decline));
+            }
+            Some((_, time_control)) => {
+                button(&format!("Proposed {}", time_control_name(time_control)), None)
+            }
+            None => {
+                let time_control = next_preset(&TimeControl::PRESETS, player_state.time_control);
+                let msg = ClientMessage::TimeControl(OfferAction::Offer, time_control);
+                button(&format!("Propose {}", time_control_name(time_control)), Some(msg));
+            }
+        }
+    }
+
+    let font_size = 32.0;
+    let mut clicked = None;
+    for (i, (label, msg)) in buttons.into_iter().enumerate() {
+        let size = measure_text(&label, None, font_size as u16, 1.0);
+        let width = size.width + font_size;
+        let height = font_size * 1.5;
+        let x = screen_width() - width - CHAT_MARGIN;
+        let y = CHAT_MARGIN + (height + CHAT_MARGIN / 2.0) * i as f32;
+        let hovered = mouse_x > x && mouse_x < x + width && mouse_y > y && mouse_y < y + height;
+        // offers waiting on an answer are shown greyed out, there's nothing to click
+        let color = match (&msg, hovered) {
+            (None, _) => GRAY,
+            (Some(_), true) => WHITE,
+            (Some(_), false) => BEIGE,
+        };
+        draw_rectangle_lines(x, y, width, height, 2.0, color);
+        draw_text(label, x + font_size / 2.0, y + font_size, font_size, color);
+        if hovered && is_mouse_button_pressed(MouseButton::Left) {
+            clicked = msg;
+        }
+    }
+    clicked
+}
+
 pub enum Scene {
     InGame,
     MainMenu,
     TeamPicker,
+    RulesPicker,
+    TimeControlPicker,
+    Replay,
+    RoomPicker,
+    WaitingForOpponent,
+    Reconnecting,
     EnterIp,
+    StartingServer,
+    BrowseLan,
+    ConnectionError,
 }
 
 pub struct ResourceBundle {
@@ -356,18 +680,365 @@ impl TeamPickerMenuState {
     }
 }
 
+struct RulesPickerMenuState {
+    selected_rules_index: u32,
+    selected_time_control_index: u32,
+    // the menu option that led to the rules picker, so we know where to go once rules are chosen
+    next: MenuOption,
+    // hosts pick a time control after the rules, so hang on to the rules in the meantime
+    rules: RuleSet,
+}
+
+impl RulesPickerMenuState {
+    pub fn new() -> Self {
+        Self {
+            selected_rules_index: 0,
+            selected_time_control_index: 0,
+            next: MenuOption::LocalGame,
+            rules: RuleSet::default(),
+        }
+    }
+}
+
+struct RoomPickerState {
+    selected_index: u32,
+    rooms: Vec<RoomInfo>,
+    // set once we've asked for a room, so that being put in the default one on connecting doesn't
+    // skip the picker
+    entering: bool,
+}
+
+impl RoomPickerState {
+    pub fn new() -> Self {
+        Self {
+            selected_index: 0,
+            rooms: Vec::new(),
+            entering: false,
+        }
+    }
+}
+
+struct LanBrowserState {
+    selected_index: u32,
+    // None if we couldn't listen for announcements, in which case error says why
+    browser: Option<LanBrowser>,
+    error: Option<String>,
+}
+
+impl LanBrowserState {
+    // starts listening straight away; the port is let go again when this is dropped
+    pub fn new() -> Self {
+        match LanBrowser::new() {
+            Ok(browser) => Self {
+                selected_index: 0,
+                browser: Some(browser),
+                error: None,
+            },
+            Err(e) => {
+                error!("Could not listen for LAN games: {e}");
+                Self {
+                    selected_index: 0,
+                    browser: None,
+                    error: Some(format!("Could not listen for LAN games: {e}")),
+                }
+            }
+        }
+    }
+}
+
+enum RoomChoice {
+    Join(String),
+    Create,
+    Refresh,
+}
+
+struct ReconnectState {
+    // where we were and the token for reclaiming our seat there
+    addr: String,
+    session_token: String,
+    // when the connection dropped, in seconds since the client started
+    started: f64,
+    next_attempt: f64,
+    attempts: u32,
+    pending: Option<PendingConnection>,
+}
+
+impl ReconnectState {
+    pub fn new(addr: String, session_token: String, now: f64) -> Self {
+        Self {
+            addr,
+            session_token,
+            started: now,
+            next_attempt: now,
+            attempts: 0,
+            pending: None,
+        }
+    }
+}
+
+// how far we've got towards connecting to a server we're hosting
+struct HostState {
+    port: u16,
+    // what the server was started with, in case it has to be started again
+    rules: RuleSet,
+    time_control: Option<TimeControl>,
+    next_attempt: f64,
+    attempts: u32,
+    pending: Option<PendingConnection>,
+}
+
+impl HostState {
+    pub fn new(now: f64, port: u16, rules: RuleSet, time_control: Option<TimeControl>) -> Self {
+        Self {
+            port,
+            rules,
+            time_control,
+            next_attempt: now,
+            attempts: 0,
+            pending: None,
+        }
+    }
+}
+
+// what "Retry" on the connection error screen does
+enum Retry {
+    Connect(String),
+    Host(RuleSet, Option<TimeControl>),
+}
+
+// something went wrong with the network badly enough that we can't carry on where we were
+struct ConnectionErrorState {
+    reason: String,
+    // None when trying again wouldn't help, e.g. the server runs a different version
+    retry: Option<Retry>,
+    selected_index: u32,
+}
+
+impl ConnectionErrorState {
+    pub fn new(reason: String, retry: Option<Retry>) -> Self {
+        Self {
+            reason,
+            retry,
+            selected_index: 0,
+        }
+    }
+}
+
+enum ErrorChoice {
+    Retry,
+    Back,
+}
+
+// short messages from the server, such as why a move was turned down, shown for a few seconds
+struct StatusState {
+    // text and when it was shown, oldest first
+    messages: VecDeque<(String, f64)>,
+}
+
+impl StatusState {
+    fn new() -> Self {
+        Self {
+            messages: VecDeque::new(),
+        }
+    }
+
+    fn push(&mut self, text: String, now: f64) {
+        if self.messages.len() == STATUS_HISTORY {
+            self.messages.pop_front();
+        }
+        self.messages.push_back((text, now));
+    }
+}
+
+struct ChatState {
+    // sender and text, oldest first
+    messages: VecDeque<(String, String)>,
+    input: TextInput,
+}
+
+impl ChatState {
+    pub fn new() -> Self {
+        Self {
+            messages: VecDeque::new(),
+            input: TextInput::new(MAX_CHAT_LEN),
+        }
+    }
+
+    fn push(&mut self, sender: String, text: String) {
+        if self.messages.len() == CHAT_HISTORY {
+            self.messages.pop_front();
+        }
+        self.messages.push_back((sender, text));
+    }
+}
+
+fn read_layout(path: &Path) -> Result<String, String> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("Could not read board {}: {e}", path.display()))?;
+    parse_layout(&text).map_err(|e| format!("Invalid board {}: {e}", path.display()))
+}
+
+// a recorded game, stepped through one move at a time
+struct ReplayState {
+    layout: String,
+    rules: RuleSet,
+    moves: Vec<(Team, BoardSquareCoords, BoardSquareCoords)>,
+    // how many of the moves are on the board
+    shown: usize,
+}
+
+// replays are protocol lines as sent by the server, so a game can be recorded from a transcript of
+// any connection; lines other than board, rules, sync and move are skipped
+fn load_replay(path: &Path) -> Result<ReplayState, String> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("Could not read replay {}: {e}", path.display()))?;
+    let mut replay = ReplayState {
+        layout: DEFAULT_LAYOUT.to_string(),
+        rules: RuleSet::default(),
+        moves: Vec::new(),
+        shown: 0,
+    };
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let msg = line.parse::<ProtocolMessage>().map_err(|e| {
+            format!("Invalid replay {} at line {}: {e}", path.display(), i + 1)
+        })?;
+        match msg {
+            ProtocolMessage::Board(layout) => replay.layout = layout,
+            // a new game starts whenever the rules are set
+            ProtocolMessage::Rules(rules) => {
+                replay.rules = rules;
+                replay.moves.clear();
+            }
+            ProtocolMessage::Sync(rules, moves) => {
+                replay.rules = rules;
+                replay.moves = moves;
+            }
+            ProtocolMessage::Move(team, from, to) => replay.moves.push((team, from, to)),
+            _ => {}
+        }
+    }
+    Ok(replay)
+}
+
+// returns true when a different move has been stepped to, and the board needs rebuilding
+fn render_replay_controls(state: &mut ReplayState) -> bool {
+    let shown = state.shown;
+    if is_key_pressed(KeyCode::Right) || is_key_pressed(KeyCode::Space) {
+        state.shown = (state.shown + 1).min(state.moves.len());
+    }
+    if is_key_pressed(KeyCode::Left) {
+        state.shown = state.shown.saturating_sub(1);
+    }
+    if is_key_pressed(KeyCode::Home) {
+        state.shown = 0;
+    }
+    if is_key_pressed(KeyCode::End) {
+        state.shown = state.moves.len();
+    }
+    let s = format!(
+        "Replay: move {} of {} (left/right to step through, home/end to jump)",
+        state.shown,
+        state.moves.len()
+    );
+    let size = measure_text(&s, None, CHAT_FONT_SIZE as u16, 1.0);
+    draw_text(
+        &s,
+        (screen_width() - size.width) / 2.0,
+        screen_height() - CHAT_MARGIN,
+        CHAT_FONT_SIZE,
+        GRAY,
+    );
+    state.shown != shown
+}
+
+// keeps an eye on the connection to the server; times are in seconds since the client started
+struct HeartbeatState {
+    last_heard: f64,
+    last_ping: f64,
+    // also the token for the next ping
+    pings_sent: u64,
+    // the token of the ping we're waiting to have answered, and when it was sent
+    pending: Option<(u64, f64)>,
+    // round trip time to the server as of the last answered ping
+    latency: Option<f64>,
+}
+
+impl HeartbeatState {
+    pub fn new(now: f64) -> Self {
+        Self {
+            last_heard: now,
+            last_ping: now,
+            pings_sent: 0,
+            pending: None,
+            latency: None,
+        }
+    }
+}
+
+// a session with a server, made when we connect and torn down when we leave or lose the connection
+struct Connection {
+    transport: Box<dyn Transport>,
+    // host:port, for reconnecting and for telling the player where they were
+    addr: String,
+    // what the server agreed to in its hello
+    capabilities: Vec<Capability>,
+    // lets us reclaim our seat if the connection drops mid-game
+    session_token: Option<String>,
+    heartbeat: HeartbeatState,
+}
+
+impl Connection {
+    fn new(transport: Box<dyn Transport>, addr: String, now: f64) -> Self {
+        Self {
+            transport,
+            addr,
+            capabilities: Vec::new(),
+            session_token: None,
+            heartbeat: HeartbeatState::new(now),
+        }
+    }
+
+    fn send(&mut self, msg: ClientMessage) {
+        self.transport.send(msg);
+    }
+
+    fn supports(&self, capability: Capability) -> bool {
+        self.capabilities.contains(&capability)
+    }
+
+    // tells the server we're going for good, so that it gives up our seat straight away instead of
+    // holding it for us, then hangs up
+    fn leave(mut self) {
+        info!("Leaving {}", self.addr);
+        self.send(ClientMessage::Quit);
+    }
+}
+
 struct EnterIpState {
-    ip_input: String,
+    input: TextInput,
+    // why the last address didn't work out, shown under the input
+    error: Option<String>,
+    // the address we're connecting to, while the connection is made in the background
+    pending: Option<(String, PendingConnection)>,
 }
 
 impl EnterIpState {
     pub fn new() -> Self {
+        let mut input = TextInput::new(MAX_ADDRESS_LEN);
+        input.focused = true;
         Self {
-            ip_input: String::new(),
+            input,
+            error: None,
+            pending: None,
         }
     }
 }
 
+// returns the address once the player asks to connect to it
 fn render_enter_ip(resources: &ResourceBundle, state: &mut EnterIpState) -> Option<String> {
     let (screen_width, screen_height) = (screen_width(), screen_height());
     let (mouse_x, mouse_y) = mouse_position();
@@ -403,46 +1074,43 @@ fn render_enter_ip(resources: &ResourceBundle, state: &mut EnterIpState) -> Opti
         },
     );
     draw_text(
-        format!("Enter IP (default port: {PORT}):").as_str(),
+        format!("Enter address (default port: {PORT}):").as_str(),
         input_box_x + 50.0,
         input_box_y + 50.0,
         MENU_FONT_SIZE,
         WHITE,
     );
-
-    let TextDimensions {
-        width: ip_input_width,
-        height: ip_input_height,
-        ..
-    } = measure_text(state.ip_input.as_str(), None, 64u16, 1.0);
-    draw_text(
-        state.ip_input.as_str(),
-        input_box_x + ((input_box_width - ip_input_width) / 2.0),
-        input_box_y + ip_input_height + 110.0,
-        64.0,
-        BLACK,
+    state.input.draw(
+        input_box_x + 50.0,
+        input_box_y + 80.0,
+        input_box_width - 100.0,
+        MENU_FONT_SIZE,
     );
+    let status = match (&state.pending, &state.error) {
+        (Some((addr, _)), _) => Some((format!("Connecting to {addr}..."), GRAY)),
+        (None, Some(error)) => Some((error.clone(), RED)),
+        (None, None) => None,
+    };
+    if let Some((status, color)) = status {
+        let size = measure_text(&status, None, CHAT_FONT_SIZE as u16, 1.0);
+        draw_text(
+            &status,
+            (screen_width - size.width) / 2.0,
+            input_box_y + input_box_height + CHAT_FONT_SIZE,
+            CHAT_FONT_SIZE,
+            color,
+        );
+    }
+    // one connection attempt at a time
+    if state.pending.is_some() {
+        return None;
+    }
 
-    if let Some(key) = get_last_key_pressed() {
-        match key {
-            KeyCode::Backspace => {
-                state.ip_input.pop();
-            }
-            KeyCode::Semicolon => {
-                // I know, I know.  I'm hard-coding a keyboard layout. Sorry!
-                if is_key_down(KeyCode::LeftShift) || is_key_down(KeyCode::RightShift) {
-                    state.ip_input += ":";
-                }
-            }
-            KeyCode::Period => {
-                state.ip_input += ".";
-            }
-            _ => {
-                if let Some(n) = get_num(key) {
-                    state.ip_input += format!("{n}").as_str();
-                }
-            }
-        }
+    // the input empties itself when enter is pressed, but we'd rather keep what was typed in case
+    // it needs correcting
+    if let Some(text) = state.input.update() {
+        state.input.text = text.clone();
+        return Some(text);
     }
 
     let item_width = menu_item_bg.width();
@@ -464,10 +1132,9 @@ fn render_enter_ip(resources: &ResourceBundle, state: &mut EnterIpState) -> Opti
         && mouse_x < item_x + item_width
         && mouse_y > item_y
         && mouse_y < item_y + item_height
+        && is_mouse_button_pressed(MouseButton::Left)
     {
-        if is_mouse_button_pressed(MouseButton::Left) {
-            return Some(state.ip_input.clone());
-        }
+        return Some(state.input.text.clone());
     }
     None
 }
@@ -532,180 +1199,1211 @@ fn render_team_picker(resources: &ResourceBundle, state: &mut TeamPickerMenuStat
     None
 }
 
-#[macroquad::main(conf)]
-async fn main() {
-    tracing_subscriber::fmt()
-        .with_max_level(tracing::Level::DEBUG)
-        .init();
-    let mut game_state = GameState::new();
-    let mut player_state = PlayerState::new();
-    let mut menu_state = MenuState::new();
-    let mut team_menu_state = TeamPickerMenuState::new();
-    let mut connected = false;
-    let (netrecv_tx, netrecv_rx) = channel::<String>();
-    let (netsend_tx, netsend_rx) = channel::<String>();
-    let (netconn_tx, netconn_rx) = sync_channel::<TcpStream>(1);
-    let mut scene = Scene::MainMenu;
-    let mut _child: Option<ChildGuard> = None;
-    let logo = load_texture("logo.png").await.unwrap();
-    let menu_item_bg = load_texture("menu-item-bg.png").await.unwrap();
-    let input_box = load_texture("input-box.png").await.unwrap();
-    let resources = ResourceBundle {
-        logo,
-        menu_item_bg,
-        input_box,
-    };
-    let mut enter_ip_state = EnterIpState::new();
-    info!("Started Guards! client");
-
-    thread::spawn(move || {
-        let conn = netconn_rx.recv().expect("Could not receive connection from networking thread");
-        let mut sendconn = conn.try_clone().expect("Could not clone stream");
-        thread::spawn(move || {
-            for line in netsend_rx {
-                let _ = sendconn
-                    .write_all(line.as_bytes())
-                    .expect("Could not send command to server");
-            }
-        });
-        let netrecv_tx = netrecv_tx.clone();
-        let conn = conn.try_clone().expect("Could not clone stream");
-        thread::spawn(move || {
-            let reader = std::io::BufReader::new(conn);
-            for line in reader.lines() {
-                match line {
-                    Ok(line) => {
-                        netrecv_tx
-                            .send(line)
-                            .expect("Could not send line to main thread");
-                    }
-                    Err(e) => {
-                        error!("Error reading from stream: {:?}", e);
-                    }
-                }
-            }
-        });
-    });
-
-    loop {
-        // --- frame init ---
-        let _delta_time = get_frame_time();
-        let (mouse_x, mouse_y) = mouse_position();
-
-        // --- general input handling ---
-        if is_key_pressed(KeyCode::Q) {
-            // todo: should we also disconnect from server if we're connected? maybe also kill the
-            // server child process if we're the host?
-            game_state.reset();
-            scene = Scene::MainMenu;
-        }
+fn render_waiting_for_opponent(player_state: &PlayerState, rules: &RuleSet, hosting_port: Option<u16>) {
+    let (screen_width, screen_height) = (screen_width(), screen_height());
+    let label = "Waiting for opponent...";
+    let label_size = measure_text(label, None, MENU_FONT_SIZE as u16, 1.0);
+    draw_text(
+        label,
+        (screen_width - label_size.width) / 2.0,
+        screen_height / 2.0,
+        MENU_FONT_SIZE,
+        WHITE,
+    );
+    if let Some(team) = player_state.playing_as {
+        let s = format!("Playing as: {team}");
+        let size = measure_text(s.as_str(), None, MENU_FONT_SIZE as u16, 1.0);
+        draw_text(
+            s.as_str(),
+            (screen_width - size.width) / 2.0,
+            screen_height / 2.0 + MENU_FONT_SIZE * 1.5,
+            MENU_FONT_SIZE,
+            GRAY,
+        );
+    }
+    // the port was picked by the OS, so whoever wants to join us needs telling
+    if let Some(port) = hosting_port {
+        let s = format!("Hosting on port {port}");
+        let size = measure_text(s.as_str(), None, MENU_FONT_SIZE as u16, 1.0);
+        draw_text(
+            s.as_str(),
+            (screen_width - size.width) / 2.0,
+            screen_height / 2.0 + MENU_FONT_SIZE * 3.0,
+            MENU_FONT_SIZE,
+            GRAY,
+        );
+    }
+    // what we'd be playing if the game started now; either player can propose something else
+    let s = format!("{rules} rules, {}", time_control_name(player_state.time_control));
+    let size = measure_text(s.as_str(), None, MENU_FONT_SIZE as u16, 1.0);
+    draw_text(
+        s.as_str(),
+        (screen_width - size.width) / 2.0,
+        screen_height / 2.0 + MENU_FONT_SIZE * 4.5,
+        MENU_FONT_SIZE,
+        GRAY,
+    );
+}
 
-        // --- rendering ---
-        clear_background(BLACK);
-        match scene {
-            Scene::EnterIp => {
-                let ip = render_enter_ip(&resources, &mut enter_ip_state);
-                if let Some(ip) = ip {
-                    let stream =
-                        TcpStream::connect(ip.as_str()).expect("Failed to connect to server");
-                    let _ = netconn_tx
-                        .send(stream)
-                        .expect("Could not send connection to networking thread");
-                    connected = true; 
-                    scene = Scene::TeamPicker;
-                }
-            }
-            Scene::TeamPicker => {
-                let team = render_team_picker(&resources, &mut team_menu_state);
-                if let Some(team) = team {
-                    if connected {
-                        netsend_tx
-                            .send(format!("join {}\n", team.as_network_string()))
-                            .expect("Could not send network command to networking thread");
+// a list of named presets to choose from; returns the index of the chosen one
+fn render_preset_picker(
+    resources: &ResourceBundle,
+    title: &str,
+    names: &[&str],
+    selected_index: &mut u32,
+) -> Option<usize> {
+    let (screen_width, screen_height) = (screen_width(), screen_height());
+    let (mouse_x, mouse_y) = mouse_position();
+    let ResourceBundle { menu_item_bg, .. } = resources;
+    let last_index = names.len() as u32 - 1;
+    if is_key_pressed(KeyCode::Down) {
+        *selected_index = (*selected_index + 1).clamp(0, last_index);
+    }
+    if is_key_pressed(KeyCode::Up) {
+        *selected_index = (*selected_index as i32 - 1).clamp(0, last_index as i32) as u32;
+    }
+    if is_key_pressed(KeyCode::Enter) {
+        return Some(*selected_index as usize);
+    }
+    draw_text(title, 0.0, 32.0, 32.0, WHITE);
+    for (i, name) in names.iter().enumerate() {
+        let item_width = menu_item_bg.width();
+        let item_height = menu_item_bg.height();
+        let item_x = (screen_width - item_width) / 2.0;
+        let item_y = screen_height / 2.0 + (item_height * i as f32);
+        let texture_color = if *selected_index == i as u32 {
+            WHITE
+        } else {
+            GRAY
+        };
+        draw_texture(menu_item_bg, item_x, item_y, texture_color);
+        let item_text_size = measure_text(name, None, MENU_FONT_SIZE as u16, 1.0);
+        draw_text(
+            name,
+            item_x + ((menu_item_bg.width() - item_text_size.width) / 2.0),
+            item_y + (menu_item_bg.height() / 2.0),
+            MENU_FONT_SIZE,
+            BEIGE,
+        );
+        if mouse_x > item_x
+            && mouse_x < item_x + item_width
+            && mouse_y > item_y
+            && mouse_y < item_y + item_height
+        {
+            *selected_index = i as u32;
+            if is_mouse_button_pressed(MouseButton::Left) {
+                return Some(i);
+            }
+        }
+    }
+    None
+}
+
+fn render_rules_picker(
+    resources: &ResourceBundle,
+    state: &mut RulesPickerMenuState,
+) -> Option<RuleSet> {
+    let names = RuleSet::PRESETS.map(|(name, _)| name);
+    render_preset_picker(resources, "Choose rules:", &names, &mut state.selected_rules_index)
+        .map(|i| RuleSet::PRESETS[i].1)
+}
+
+fn render_time_control_picker(
+    resources: &ResourceBundle,
+    state: &mut RulesPickerMenuState,
+) -> Option<Option<TimeControl>> {
+    let names = TimeControl::PRESETS.map(|(name, _)| name);
+    render_preset_picker(
+        resources,
+        "Choose time control (minutes+seconds per move):",
+        &names,
+        &mut state.selected_time_control_index,
+    )
+    .map(|i| TimeControl::PRESETS[i].1)
+}
+
+fn render_room_picker(resources: &ResourceBundle, state: &mut RoomPickerState) -> Option<RoomChoice> {
+    let (screen_width, screen_height) = (screen_width(), screen_height());
+    let (mouse_x, mouse_y) = mouse_position();
+    let ResourceBundle { menu_item_bg, .. } = resources;
+    let mut items: Vec<(RoomChoice, String)> = state
+        .rooms
+        .iter()
+        .map(|room| {
+            (
+                RoomChoice::Join(room.name.clone()),
+                format!("{} ({}/2, {} watching)", room.name, room.players, room.spectators),
+            )
+        })
+        .collect();
+    items.push((RoomChoice::Create, "New room".to_string()));
+    items.push((RoomChoice::Refresh, "Refresh".to_string()));
+    let last_index = items.len() as u32 - 1;
+    state.selected_index = state.selected_index.min(last_index);
+    if is_key_pressed(KeyCode::Down) {
+        state.selected_index = (state.selected_index + 1).clamp(0, last_index);
+    }
+    if is_key_pressed(KeyCode::Up) {
+        state.selected_index = (state.selected_index as i32 - 1).clamp(0, last_index as i32) as u32;
+    }
+    if is_key_pressed(KeyCode::Enter) {
+        return Some(items.swap_remove(state.selected_index as usize).0);
+    }
+    draw_text("Choose a room:", 0.0, 32.0, 32.0, WHITE);
+    let item_width = menu_item_bg.width();
+    let item_height = menu_item_bg.height();
+    let item_x = (screen_width - item_width) / 2.0;
+    // keep the list centred however many rooms there are
+    let list_y = (screen_height - item_height * items.len() as f32) / 2.0;
+    let mut choice = None;
+    for (i, (_, label)) in items.iter().enumerate() {
+        let item_y = list_y + (item_height * i as f32);
+        let texture_color = if state.selected_index == i as u32 {
+            WHITE
+        } else {
+            GRAY
+        };
+        draw_texture(menu_item_bg, item_x, item_y, texture_color);
+        let item_text_size = measure_text(label, None, MENU_FONT_SIZE as u16, 1.0);
+        draw_text(
+            label,
+            item_x + ((menu_item_bg.width() - item_text_size.width) / 2.0),
+            item_y + (menu_item_bg.height() / 2.0),
+            MENU_FONT_SIZE,
+            BEIGE,
+        );
+        if mouse_x > item_x
+            && mouse_x < item_x + item_width
+            && mouse_y > item_y
+            && mouse_y < item_y + item_height
+        {
+            state.selected_index = i as u32;
+            if is_mouse_button_pressed(MouseButton::Left) {
+                choice = Some(i);
+            }
+        }
+    }
+    choice.map(|i| items.swap_remove(i).0)
+}
+
+// the servers announcing themselves on the local network; returns the address of the chosen one
+fn render_lan_browser(resources: &ResourceBundle, state: &mut LanBrowserState) -> Option<SocketAddr> {
+    let (screen_width, screen_height) = (screen_width(), screen_height());
+    let (mouse_x, mouse_y) = mouse_position();
+    let ResourceBundle { menu_item_bg, .. } = resources;
+    draw_text("LAN games:", 0.0, 32.0, 32.0, WHITE);
+    let hint = "Press Escape to go back";
+    let hint_size = measure_text(hint, None, CHAT_FONT_SIZE as u16, 1.0);
+    draw_text(
+        hint,
+        (screen_width - hint_size.width) / 2.0,
+        screen_height - CHAT_MARGIN,
+        CHAT_FONT_SIZE,
+        GRAY,
+    );
+    let games = match (&mut state.browser, &state.error) {
+        (Some(browser), _) => {
+            browser.poll(Instant::now());
+            browser.games()
+        }
+        (None, error) => {
+            let error = error.as_deref().unwrap_or_default();
+            let size = measure_text(error, None, MENU_FONT_SIZE as u16, 1.0);
+            draw_text(error, (screen_width - size.width) / 2.0, screen_height / 2.0, MENU_FONT_SIZE, RED);
+            return None;
+        }
+    };
+    if games.is_empty() {
+        let label = "Looking for games on your network...";
+        let size = measure_text(label, None, MENU_FONT_SIZE as u16, 1.0);
+        draw_text(label, (screen_width - size.width) / 2.0, screen_height / 2.0, MENU_FONT_SIZE, GRAY);
+        return None;
+    }
+    let last_index = games.len() as u32 - 1;
+    state.selected_index = state.selected_index.min(last_index);
+    if is_key_pressed(KeyCode::Down) {
+        state.selected_index = (state.selected_index + 1).clamp(0, last_index);
+    }
+    if is_key_pressed(KeyCode::Up) {
+        state.selected_index = (state.selected_index as i32 - 1).clamp(0, last_index as i32) as u32;
+    }
+    // a server speaking another version would only turn us away
+    let joinable = |i: usize| games[i].announcement.protocol_version == PROTOCOL_VERSION;
+    if is_key_pressed(KeyCode::Enter) && joinable(state.selected_index as usize) {
+        return Some(games[state.selected_index as usize].addr);
+    }
+    let item_width = menu_item_bg.width();
+    let item_height = menu_item_bg.height();
+    let item_x = (screen_width - item_width) / 2.0;
+    let list_y = (screen_height - item_height * games.len() as f32) / 2.0;
+    let mut choice = None;
+    for (i, game) in games.iter().enumerate() {
+        let item_y = list_y + (item_height * i as f32);
+        let texture_color = if state.selected_index == i as u32 {
+            WHITE
+        } else {
+            GRAY
+        };
+        draw_texture(menu_item_bg, item_x, item_y, texture_color);
+        let announcement = &game.announcement;
+        let label = if joinable(i) {
+            format!("{} ({} open seats)", announcement.name, announcement.open_seats)
+        } else {
+            format!("{} (different version)", announcement.name)
+        };
+        let item_text_size = measure_text(&label, None, MENU_FONT_SIZE as u16, 1.0);
+        draw_text(
+            &label,
+            item_x + ((menu_item_bg.width() - item_text_size.width) / 2.0),
+            item_y + (menu_item_bg.height() / 2.0),
+            MENU_FONT_SIZE,
+            if joinable(i) { BEIGE } else { GRAY },
+        );
+        if mouse_x > item_x
+            && mouse_x < item_x + item_width
+            && mouse_y > item_y
+            && mouse_y < item_y + item_height
+        {
+            state.selected_index = i as u32;
+            if is_mouse_button_pressed(MouseButton::Left) && joinable(i) {
+                choice = Some(game.addr);
+            }
+        }
+    }
+    // the selected server's address and rooms, which don't fit on its button
+    let selected = &games[state.selected_index as usize];
+    let details = format!("{}, {} rooms", selected.addr, selected.announcement.rooms);
+    let details_size = measure_text(&details, None, CHAT_FONT_SIZE as u16, 1.0);
+    draw_text(
+        &details,
+        (screen_width - details_size.width) / 2.0,
+        list_y + item_height * games.len() as f32 + CHAT_FONT_SIZE * 1.5,
+        CHAT_FONT_SIZE,
+        GRAY,
+    );
+    choice
+}
+
+// splits text into lines that fit within width, breaking between words where possible
+fn wrap_text(text: &str, width: f32, font_size: f32) -> Vec<String> {
+    let fits = |line: &str| measure_text(line, None, font_size as u16, 1.0).width <= width;
+    let mut lines = Vec::new();
+    let mut line = String::new();
+    for word in text.split(' ') {
+        let candidate = if line.is_empty() {
+            word.to_string()
+        } else {
+            format!("{line} {word}")
+        };
+        if fits(&candidate) {
+            line = candidate;
+            continue;
+        }
+        if !line.is_empty() {
+            lines.push(std::mem::take(&mut line));
+        }
+        // a single word that's too long on its own gets broken wherever it has to be
+        for c in word.chars() {
+            if !fits(&format!("{line}{c}")) && !line.is_empty() {
+                lines.push(std::mem::take(&mut line));
+            }
+            line.push(c);
+        }
+    }
+    if !line.is_empty() {
+        lines.push(line);
+    }
+    lines
+}
+
+// the chat panel in the bottom left corner; returns a message to send once one has been typed
+fn render_chat(state: &mut ChatState) -> Option<String> {
+    let (screen_width, screen_height) = (screen_width(), screen_height());
+    // whatever space the board leaves on its left
+    let width = ((screen_width - 7.0 * SQUARE_SIZE) / 2.0 - CHAT_MARGIN * 2.0).max(200.0);
+    let x = CHAT_MARGIN;
+    let input_y = screen_height - CHAT_MARGIN - CHAT_FONT_SIZE * 1.25;
+
+    // newest at the bottom, stopping once we run out of room
+    let mut y = input_y - CHAT_FONT_SIZE / 2.0;
+    'messages: for (sender, text) in state.messages.iter().rev() {
+        let lines = wrap_text(&format!("{sender}: {text}"), width, CHAT_FONT_SIZE);
+        for line in lines.iter().rev() {
+            if y < screen_height / 2.0 {
+                break 'messages;
+            }
+            draw_text(line, x, y, CHAT_FONT_SIZE, if sender == "server" { RED } else { WHITE });
+            y -= CHAT_FONT_SIZE;
+        }
+    }
+
+    state.input.draw(x, input_y, width, CHAT_FONT_SIZE);
+    if !state.input.focused {
+        if state.input.text.is_empty() {
+            draw_text(
+                "Press enter to chat",
+                x + CHAT_FONT_SIZE / 4.0,
+                input_y + CHAT_FONT_SIZE,
+                CHAT_FONT_SIZE,
+                GRAY,
+            );
+        }
+        if is_key_pressed(KeyCode::Enter) || is_key_pressed(KeyCode::KpEnter) {
+            state.input.focused = true;
+        }
+        return None;
+    }
+    if is_key_pressed(KeyCode::Escape) {
+        state.input.focused = false;
+        state.input.text.clear();
+        return None;
+    }
+    let text = state.input.update()?;
+    state.input.focused = false;
+    let text = text.trim();
+    (!text.is_empty()).then(|| text.to_string())
+}
+
+// along the top of the screen, newest at the bottom, fading out just before they go
+fn render_status(state: &mut StatusState, now: f64) {
+    state.messages.retain(|(_, shown)| now - shown < STATUS_SECS);
+    for (i, (text, shown)) in state.messages.iter().enumerate() {
+        let alpha = ((STATUS_SECS - (now - shown)) as f32).min(1.0);
+        let size = measure_text(text, None, CHAT_FONT_SIZE as u16, 1.0);
+        let x = (screen_width() - size.width) / 2.0;
+        let y = CHAT_MARGIN + (i as f32 + 1.0) * CHAT_FONT_SIZE * 1.5;
+        draw_rectangle(
+            x - CHAT_MARGIN / 2.0,
+            y - size.offset_y - CHAT_MARGIN / 4.0,
+            size.width + CHAT_MARGIN,
+            size.height + CHAT_MARGIN / 2.0,
+            Color::new(0.0, 0.0, 0.0, 0.7 * alpha),
+        );
+        draw_text(text, x, y, CHAT_FONT_SIZE, Color { a: alpha, ..RED });
+    }
+}
+
+// in the bottom right corner, out of the way of the board and chat
+fn render_latency(latency: f64) {
+    let s = format!("Ping: {:.0}ms", latency * 1000.0);
+    let size = measure_text(&s, None, CHAT_FONT_SIZE as u16, 1.0);
+    draw_text(
+        &s,
+        screen_width() - size.width - CHAT_MARGIN,
+        screen_height() - CHAT_MARGIN,
+        CHAT_FONT_SIZE,
+        GRAY,
+    );
+}
+
+fn render_reconnecting(state: &ReconnectState, now: f64) {
+    let (screen_width, screen_height) = (screen_width(), screen_height());
+    let remaining = (SEAT_GRACE_PERIOD_SECS as f64 - (now - state.started)).max(0.0);
+    let lines = [
+        "Connection lost, reconnecting...".to_string(),
+        format!("Your seat is held for another {remaining:.0}s"),
+        format!("Attempts so far: {}", state.attempts),
+    ];
+    for (i, line) in lines.iter().enumerate() {
+        let size = measure_text(line, None, MENU_FONT_SIZE as u16, 1.0);
+        draw_text(
+            line,
+            (screen_width - size.width) / 2.0,
+            screen_height / 2.0 + MENU_FONT_SIZE * 1.5 * i as f32,
+            MENU_FONT_SIZE,
+            if i == 0 { WHITE } else { GRAY },
+        );
+    }
+}
+
+fn render_starting_server(state: &HostState) {
+    let (screen_width, screen_height) = (screen_width(), screen_height());
+    let lines = [
+        "Starting server...".to_string(),
+        format!("Connecting to port {}, attempt {}", state.port, state.attempts.max(1)),
+        "Press Escape to cancel".to_string(),
+    ];
+    for (i, line) in lines.iter().enumerate() {
+        let size = measure_text(line, None, MENU_FONT_SIZE as u16, 1.0);
+        draw_text(
+            line,
+            (screen_width - size.width) / 2.0,
+            screen_height / 2.0 + MENU_FONT_SIZE * 1.5 * i as f32,
+            MENU_FONT_SIZE,
+            if i == 0 { WHITE } else { GRAY },
+        );
+    }
+}
+
+fn render_connection_error(
+    resources: &ResourceBundle,
+    state: &mut ConnectionErrorState,
+) -> Option<ErrorChoice> {
+    let (screen_width, screen_height) = (screen_width(), screen_height());
+    let (mouse_x, mouse_y) = mouse_position();
+    let ResourceBundle { menu_item_bg, .. } = resources;
+    let title = "Connection problem";
+    let title_size = measure_text(title, None, MENU_FONT_SIZE as u16, 1.0);
+    let mut y = screen_height / 3.0;
+    draw_text(title, (screen_width - title_size.width) / 2.0, y, MENU_FONT_SIZE, RED);
+    for line in wrap_text(&state.reason, screen_width * 0.8, CHAT_FONT_SIZE) {
+        y += CHAT_FONT_SIZE * 1.25;
+        let size = measure_text(&line, None, CHAT_FONT_SIZE as u16, 1.0);
+        draw_text(&line, (screen_width - size.width) / 2.0, y, CHAT_FONT_SIZE, GRAY);
+    }
+    if is_key_pressed(KeyCode::Escape) {
+        return Some(ErrorChoice::Back);
+    }
+    let mut items = Vec::new();
+    if state.retry.is_some() {
+        items.push((ErrorChoice::Retry, "Retry"));
+    }
+    items.push((ErrorChoice::Back, "Back to menu"));
+    let last_index = items.len() as u32 - 1;
+    state.selected_index = state.selected_index.min(last_index);
+    if is_key_pressed(KeyCode::Down) {
+        state.selected_index = (state.selected_index + 1).clamp(0, last_index);
+    }
+    if is_key_pressed(KeyCode::Up) {
+        state.selected_index = (state.selected_index as i32 - 1).clamp(0, last_index as i32) as u32;
+    }
+    if is_key_pressed(KeyCode::Enter) {
+        return Some(items.swap_remove(state.selected_index as usize).0);
+    }
+    let item_width = menu_item_bg.width();
+    let item_height = menu_item_bg.height();
+    let item_x = (screen_width - item_width) / 2.0;
+    let list_y = y + CHAT_FONT_SIZE * 2.0;
+    let mut choice = None;
+    for (i, (_, label)) in items.iter().enumerate() {
+        let item_y = list_y + (item_height * i as f32);
+        let texture_color = if state.selected_index == i as u32 {
+            WHITE
+        } else {
+            GRAY
+        };
+        draw_texture(menu_item_bg, item_x, item_y, texture_color);
+        let item_text_size = measure_text(label, None, MENU_FONT_SIZE as u16, 1.0);
+        draw_text(
+            label,
+            item_x + ((menu_item_bg.width() - item_text_size.width) / 2.0),
+            item_y + (menu_item_bg.height() / 2.0),
+            MENU_FONT_SIZE,
+            BEIGE,
+        );
+        if mouse_x > item_x
+            && mouse_x < item_x + item_width
+            && mouse_y > item_y
+            && mouse_y < item_y + item_height
+        {
+            state.selected_index = i as u32;
+            if is_mouse_button_pressed(MouseButton::Left) {
+                choice = Some(i);
+            }
+        }
+    }
+    choice.map(|i| items.swap_remove(i).0)
+}
+
+// runs a server on background threads, listening on every address so that others on the network
+// can join, on ports of the OS's choosing so that it can't clash with any other server on this
+// machine
+fn start_server(rules: RuleSet, time_control: Option<TimeControl>) -> Result<host::Host, String> {
+    host::Host::start(host::HostOptions {
+        bind: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        port: 0,
+        websocket_port: 0,
+        room_defaults: RoomDefaults {
+            rules,
+            time_control,
+            layout: DEFAULT_LAYOUT.to_string(),
+        },
+        max_rooms: HOSTED_MAX_ROOMS,
+        name: hosted_server_name(),
+        announce_to: Some(IpAddr::V4(Ipv4Addr::BROADCAST)),
+    })
+}
+
+// what other players see our game called when browsing the LAN
+fn hosted_server_name() -> String {
+    std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .map(|user| format!("{user}'s game"))
+        .ok()
+        .filter(|name| is_valid_server_name(name))
+        .unwrap_or_else(|| "Guards! game".to_string())
+}
+
+#[macroquad::main(conf)]
+async fn main() {
+    let subscriber = tracing_subscriber::fmt().with_max_level(tracing::Level::DEBUG);
+    // there's no clock to read timestamps from in the browser
+    #[cfg(target_arch = "wasm32")]
+    let subscriber = subscriber.without_time();
+    subscriber.init();
+    // otherwise every client picks the same "random" room names
+    rand::srand(macroquad::miniquad::date::now().to_bits());
+    let args = args();
+    // local games are played on this, and it's what we go back to after playing on a server's board
+    let local_layout = match args.board.as_deref().map(read_layout) {
+        Some(Ok(layout)) => layout,
+        Some(Err(e)) => {
+            eprintln!("guards: {e}");
+            std::process::exit(2);
+        }
+        None => DEFAULT_LAYOUT.to_string(),
+    };
+    let mut game_state = GameState::new(RuleSet::default());
+    game_state.set_layout(local_layout.clone());
+    let mut player_state = PlayerState::new();
+    let mut menu_state = MenuState::new();
+    let mut team_menu_state = TeamPickerMenuState::new();
+    let mut rules_menu_state = RulesPickerMenuState::new();
+    let mut room_picker_state = RoomPickerState::new();
+    // None whenever we aren't connected to a server
+    let mut connection: Option<Connection> = None;
+    // set when the connection drops while we have a seat to get back to
+    let mut reconnect_state: Option<ReconnectState> = None;
+    let mut scene = Scene::MainMenu;
+    // choices made on the command line, each used up by the scene that would otherwise ask for it
+    let mut auto_connect = args.connect.as_ref().map(ServerAddress::to_string);
+    let mut auto_team = args.team;
+    // --host plays untimed
+    let mut auto_host = args.host.then_some(None);
+    let mut replay_state = match &args.replay {
+        Some(path) => match load_replay(path) {
+            Ok(replay) => Some(replay),
+            Err(e) => {
+                eprintln!("guards: {e}");
+                std::process::exit(2);
+            }
+        },
+        None => None,
+    };
+    if let Some(replay) = &replay_state {
+        game_state.set_layout(replay.layout.clone());
+        game_state.set_rules(replay.rules);
+        player_state.spectating = true;
+        scene = Scene::Replay;
+    } else if auto_connect.is_some() {
+        scene = Scene::EnterIp;
+    } else if auto_host.is_some() {
+        scene = Scene::TimeControlPicker;
+    }
+    let mut hosted_server: Option<host::Host> = None;
+    let mut host_state = HostState::new(0.0, 0, RuleSet::default(), None);
+    let mut connection_error = ConnectionErrorState::new(String::new(), None);
+    // built into the executable, so that the browser build can be served as a handful of files
+    let logo = Texture2D::from_file_with_format(include_bytes!("../logo.png"), None);
+    let menu_item_bg = Texture2D::from_file_with_format(include_bytes!("../menu-item-bg.png"), None);
+    let input_box = Texture2D::from_file_with_format(include_bytes!("../input-box.png"), None);
+    let resources = ResourceBundle {
+        logo,
+        menu_item_bg,
+        input_box,
+    };
+    let mut enter_ip_state = EnterIpState::new();
+    let mut lan_browser_state: Option<LanBrowserState> = None;
+    let mut chat_state = ChatState::new();
+    let mut status_state = StatusState::new();
+    info!("Started Guards! client");
+
+    loop {
+        // --- frame init ---
+        let _delta_time = get_frame_time();
+        let (mouse_x, mouse_y) = mouse_position();
+
+        // --- general input handling ---
+        // q is just another letter while we're typing a chat message or an address
+        if is_key_pressed(KeyCode::Q)
+            && !chat_state.input.focused
+            && !matches!(scene, Scene::EnterIp)
+        {
+            if let Some(connection) = connection.take() {
+                connection.leave();
+            }
+            reconnect_state = None;
+            // nobody can play on our server once we've left it
+            if hosted_server.take().is_some() {
+                info!("Stopped hosting");
+            }
+            // the server may have had us playing on a board of its own
+            game_state.set_layout(local_layout.clone());
+            player_state.new_game();
+            scene = Scene::MainMenu;
+        }
+
+        // --- heartbeat ---
+        if let Some(connection) = &mut connection {
+            let now = get_time();
+            let heartbeat = &mut connection.heartbeat;
+            if now - heartbeat.last_heard > PEER_TIMEOUT_SECS as f64 {
+                error!("Server has stopped responding");
+                // dropping the transport closes it, and the network handling below then deals
+                // with the disconnect like any other
+                connection.transport = Box::new(Offline);
+            } else if now - heartbeat.last_ping >= PING_INTERVAL_SECS as f64 {
+                heartbeat.last_ping = now;
+                heartbeat.pending = Some((heartbeat.pings_sent, now));
+                connection.transport.send(ClientMessage::Ping(heartbeat.pings_sent));
+                heartbeat.pings_sent += 1;
+            }
+        }
+
+        // --- network handling ---
+        while let Some(conn) = &mut connection {
+            match conn.transport.try_recv() {
+                Ok(msg) => {
+                    conn.heartbeat.last_heard = get_time();
+                    if matches!(msg, ProtocolMessage::Ping(_) | ProtocolMessage::Pong(_)) {
+                        debug!("Received: {:?}", msg);
+                    } else {
+                        info!("Received: {:?}", msg);
+                    }
+                    match msg {
+                        ProtocolMessage::Hello(version, _) if version != PROTOCOL_VERSION => {
+                            error!("Server speaks protocol {version}, we speak {PROTOCOL_VERSION}");
+                            // hang up; without a session token the disconnect isn't retried
+                            conn.session_token = None;
+                            conn.transport = Box::new(Offline);
+                            connection_error = ConnectionErrorState::new(
+                                format!(
+                                    "The server runs a different version of Guards!: it speaks protocol \
+                                     {version}, we speak {PROTOCOL_VERSION}"
+                                ),
+                                None,
+                            );
+                            scene = Scene::ConnectionError;
+                        }
+                        ProtocolMessage::Hello(version, capabilities) => {
+                            info!(
+                                "Server speaks protocol {} with capabilities {:?}",
+                                version, capabilities
+                            );
+                            conn.capabilities = capabilities;
+                        }
+                        ProtocolMessage::Board(layout) => {
+                            game_state.set_layout(layout);
+                        }
+                        ProtocolMessage::Rules(rules) => {
+                            info!("Playing with {rules} rules");
+                            game_state.set_rules(rules);
+                        }
+                        ProtocolMessage::Move(team, from, to) => {
+                            if player_state.playing_as == Some(team) {
+                                player_state.pending_move = None;
+                            }
+                            // a move lets any draw offer lapse
+                            player_state.draw_offer = None;
+                            player_state.first_move_played = true;
+                            if !game_state.make_move(team, from, to) {
+                                error!("Could not play {team}'s move from {from:?} to {to:?} on our board");
+                            }
+                        }
+                        ProtocolMessage::RoomList(rooms) => {
+                            room_picker_state.rooms = rooms;
+                        }
+                        ProtocolMessage::RoomEntered(_) => {
+                            // entering a room always means starting over with a new game
+                            player_state.playing_as = None;
+                            conn.session_token = None;
+                            chat_state.messages.clear();
+                            if matches!(scene, Scene::RoomPicker) && room_picker_state.entering {
+                                room_picker_state.entering = false;
+                                if player_state.spectating
+                                    && !conn.supports(Capability::Spectate)
+                                {
+                                    error!("Server does not support spectating");
+                                    connection_error = ConnectionErrorState::new(
+                                        format!("{} doesn't support spectating", conn.addr),
+                                        None,
+                                    );
+                                    scene = Scene::ConnectionError;
+                                } else if player_state.spectating {
+                                    conn.send(ClientMessage::Spectate);
+                                    scene = Scene::InGame;
+                                } else {
+                                    scene = Scene::TeamPicker;
+                                }
+                            }
+                        }
+                        ProtocolMessage::Sync(rules, moves) => {
+                            game_state.set_rules(rules);
+                            player_state.new_game();
+                            player_state.first_move_played = !moves.is_empty();
+                            for (team, from, to) in moves {
+                                game_state.make_move(team, from, to);
+                            }
+                        }
+                        ProtocolMessage::RoomLeft(_) => {
+                            player_state.playing_as = None;
+                            conn.session_token = None;
+                            scene = Scene::RoomPicker;
+                        }
+                        ProtocolMessage::TeamJoin(_) => {}
+                        ProtocolMessage::Session(token) => {
+                            conn.session_token = Some(token);
+                        }
+                        ProtocolMessage::Resumed(_, team) => {
+                            info!("Resumed our seat as {team}");
+                            player_state.playing_as = Some(team);
+                            player_state.spectating = false;
+                            scene = Scene::WaitingForOpponent;
+                        }
+                        ProtocolMessage::Start => {
+                            if let Scene::WaitingForOpponent = scene {
+                                scene = Scene::InGame;
+                            }
+                        }
+                        ProtocolMessage::Chat(_, sender, text) => {
+                            chat_state.push(sender, text);
+                        }
+                        ProtocolMessage::GameOver(win, reason) => {
+                            game_state.game_over = Some(win);
+                            player_state.end_reason = Some(reason);
+                            player_state.draw_offer = None;
+                        }
+                        ProtocolMessage::Draw(action, team) => {
+                            player_state.draw_offer = match action {
+                                OfferAction::Offer => Some(team),
+                                OfferAction::Accept | OfferAction::Decline => None,
+                            };
+                        }
+                        ProtocolMessage::Rematch(action, team) => {
+                            player_state.rematch_offer = match action {
+                                OfferAction::Offer => Some(team),
+                                OfferAction::Accept | OfferAction::Decline => None,
+                            };
+                        }
+                        ProtocolMessage::TimeControl(time_control) => {
+                            match time_control {
+                                Some(time_control) => info!("Playing with {time_control} time control"),
+                                None => info!("Playing untimed"),
+                            }
+                            // the clocks themselves follow in a clock message
+                            if time_control.is_none() {
+                                player_state.clock = None;
+                            }
+                            player_state.time_control = time_control;
+                        }
+                        ProtocolMessage::RulesOffer(action, team, rules) => {
+                            player_state.rules_offer = match action {
+                                OfferAction::Offer => Some((team, rules)),
+                                OfferAction::Accept | OfferAction::Decline => None,
+                            };
+                        }
+                        ProtocolMessage::TimeControlOffer(action, team, time_control) => {
+                            player_state.time_control_offer = match action {
+                                OfferAction::Offer => Some((team, time_control)),
+                                OfferAction::Accept | OfferAction::Decline => None,
+                            };
+                        }
+                        ProtocolMessage::Clock(purple_ms, white_ms, running) => {
+                            player_state.clock = Some(ClockState {
+                                purple_ms,
+                                white_ms,
+                                running,
+                                received: get_time(),
+                            });
+                        }
+                        ProtocolMessage::Ping(token) => {
+                            conn.send(ClientMessage::Pong(token));
+                        }
+                        ProtocolMessage::Pong(token) => {
+                            if let Some((_, sent)) =
+                                conn.heartbeat.pending.filter(|(expected, _)| *expected == token)
+                            {
+                                conn.heartbeat.pending = None;
+                                conn.heartbeat.latency = Some(get_time() - sent);
+                            }
+                        }
+                        ProtocolMessage::Seat(team) => {
+                            // rematches swap colours; the new board follows in a sync
+                            info!("Now playing as {team}");
+                            player_state.playing_as = Some(team);
+                        }
+                        ProtocolMessage::Error(kind, reason) => {
+                            error!("Server sent error {kind}: {reason}");
+                            match (kind, &scene) {
+                                (ProtocolError::IncompatibleVersion, _) => {
+                                    // the server will hang up on us, and trying again won't help
+                                    conn.session_token = None;
+                                    connection_error = ConnectionErrorState::new(
+                                        format!("The server runs a different version of Guards!: {reason}"),
+                                        None,
+                                    );
+                                    scene = Scene::ConnectionError;
+                                }
+                                (ProtocolError::InvalidTeam, Scene::WaitingForOpponent) => {
+                                    // someone else got to our seat first, so pick again
+                                    player_state.playing_as = None;
+                                    status_state.push(reason, get_time());
+                                    scene = Scene::TeamPicker;
+                                }
+                                (ProtocolError::InvalidMove, _) => {
+                                    // the board was never changed, so just let the player try again
+                                    player_state.pending_move = None;
+                                    player_state.selected_square = None;
+                                    status_state.push(reason, get_time());
+                                }
+                                (ProtocolError::InvalidSession, Scene::Reconnecting) => {
+                                    // our seat has gone, there's nothing left to resume
+                                    conn.session_token = None;
+                                    player_state.playing_as = None;
+                                    connection_error = ConnectionErrorState::new(
+                                        "Reconnected, but your seat had already been given up".to_string(),
+                                        Some(Retry::Connect(conn.addr.clone())),
+                                    );
+                                    scene = Scene::ConnectionError;
+                                }
+                                (ProtocolError::InvalidChat | ProtocolError::RateLimited, _) => {
+                                    chat_state.push("server".to_string(), reason);
+                                }
+                                _ => status_state.push(reason, get_time()),
+                            }
+                        }
+                    }
+                }
+                // Empty just means we haven't got anything to process
+                Err(std::sync::mpsc::TryRecvError::Empty) => break,
+                Err(std::sync::mpsc::TryRecvError::Disconnected) => {
+                    error!("Disconnected from server");
+                    let Some(Connection { addr, session_token, .. }) = connection.take() else {
+                        break;
+                    };
+                    if let Some(session_token) = session_token {
+                        reconnect_state = Some(ReconnectState::new(addr, session_token, get_time()));
+                        scene = Scene::Reconnecting;
+                    } else if !matches!(scene, Scene::ConnectionError) {
+                        connection_error = ConnectionErrorState::new(
+                            format!("Lost the connection to {addr}"),
+                            Some(Retry::Connect(addr)),
+                        );
+                        scene = Scene::ConnectionError;
+                    }
+                }
+            }
+        }
+
+        // --- rendering ---
+        clear_background(BLACK);
+        match scene {
+            Scene::EnterIp => {
+                let input = auto_connect
+                    .take()
+                    .or_else(|| render_enter_ip(&resources, &mut enter_ip_state));
+                if let Some(input) = input {
+                    match input.parse::<ServerAddress>() {
+                        Ok(address) => {
+                            // host names are looked up as part of connecting, which happens in the
+                            // background so that a slow lookup doesn't freeze the screen
+                            let addr = address.to_string();
+                            info!("Connecting to {addr}");
+                            enter_ip_state.error = None;
+                            enter_ip_state.pending =
+                                Some((addr.clone(), transport::connect_in_background(addr)));
+                        }
+                        Err(e) => enter_ip_state.error = Some(e),
+                    }
+                }
+                if let Some((addr, pending)) = &enter_ip_state.pending {
+                    match pending.try_recv() {
+                        Ok(Ok(transport)) => {
+                            let mut new_connection = Connection::new(transport, addr.clone(), get_time());
+                            new_connection.send(ClientMessage::RoomList);
+                            connection = Some(new_connection);
+                            room_picker_state.entering = false;
+                            enter_ip_state.pending = None;
+                            scene = Scene::RoomPicker;
+                        }
+                        Ok(Err(e)) => {
+                            error!("Could not connect to {addr}: {e}");
+                            connection_error = ConnectionErrorState::new(
+                                format!("Could not connect to {addr}: {e}"),
+                                Some(Retry::Connect(addr.clone())),
+                            );
+                            enter_ip_state.pending = None;
+                            scene = Scene::ConnectionError;
+                        }
+                        Err(std::sync::mpsc::TryRecvError::Empty) => {}
+                        Err(std::sync::mpsc::TryRecvError::Disconnected) => {
+                            enter_ip_state.pending = None;
+                        }
+                    }
+                } else if is_key_pressed(KeyCode::Escape) {
+                    enter_ip_state.error = None;
+                    scene = Scene::MainMenu;
+                }
+            }
+            Scene::RoomPicker => {
+                let choice = render_room_picker(&resources, &mut room_picker_state);
+                if let (Some(choice), Some(connection)) = (choice, &mut connection) {
+                    match choice {
+                        RoomChoice::Join(name) => {
+                            room_picker_state.entering = true;
+                            connection.send(ClientMessage::RoomJoin(name));
+                        }
+                        RoomChoice::Create => {
+                            let name = format!("room-{}", rand::gen_range(1000, 10000));
+                            room_picker_state.entering = true;
+                            connection.send(ClientMessage::RoomCreate(name));
+                        }
+                        RoomChoice::Refresh => {
+                            connection.send(ClientMessage::RoomList);
+                        }
                     }
+                }
+            }
+            Scene::TeamPicker => {
+                let team = auto_team
+                    .take()
+                    .or_else(|| render_team_picker(&resources, &mut team_menu_state));
+                if let Some(team) = team {
                     player_state.playing_as = Some(team);
-                    scene = Scene::InGame;
+                    if let Some(connection) = &mut connection {
+                        connection.send(ClientMessage::Join(team));
+                        connection.send(ClientMessage::Ready);
+                        scene = Scene::WaitingForOpponent;
+                    } else {
+                        scene = Scene::InGame;
+                    }
                 }
             }
-            Scene::InGame => {
-                if connected {
-                    if let Some(_) = player_state.playing_as {
-                        match netrecv_rx.try_recv() {
-                            Ok(rx_buf) => {
-                                debug!("Received: {:?}", rx_buf);
-                                if rx_buf.len() > 0 {
-                                    info!("Received: {:?}", rx_buf);
-                                    let command: Vec<&str> = rx_buf.split(' ').collect();
-                                    match command[0] {
-                                        "move" => {
-                                            if command.len() == 4 {
-                                                let team = Team::from_str(command[1]);
-                                                let from = BoardSquareCoords::from_str(command[2])
-                                                    .expect("Server sent malformed MOVE command");
-                                                let to = BoardSquareCoords::from_str(command[3])
-                                                    .expect("Server sent malformed MOVE command");
-                                                if let Ok(team) = team {
-                                                    game_state.make_move(team, from, to);
-                                                } else {
-                                                    error!(
-                                                        "Server sent malformed MOVE command {:?}",
-                                                        command
-                                                    );
-                                                }
-                                            } else {
-                                                error!(
-                                                    "Server sent malformed MOVE command {:?}",
-                                                    command
-                                                );
-                                            }
-                                        }
-                                        _ => {
-                                            error!(
-                                                "Server sent unrecognised command {:?}",
-                                                command
-                                            );
-                                        }
-                                    }
-                                }
+            Scene::Reconnecting => {
+                if let Some(reconnect) = &mut reconnect_state {
+                    let now = get_time();
+                    render_reconnecting(reconnect, now);
+                    if let Some(pending) = &reconnect.pending {
+                        match pending.try_recv() {
+                            Ok(Ok(transport)) => {
+                                info!("Reconnected to {}", reconnect.addr);
+                                let mut new_connection =
+                                    Connection::new(transport, reconnect.addr.clone(), now);
+                                new_connection.session_token = Some(reconnect.session_token.clone());
+                                new_connection.send(ClientMessage::Resume(reconnect.session_token.clone()));
+                                connection = Some(new_connection);
+                                reconnect.pending = None;
+                            }
+                            Ok(Err(e)) => {
+                                info!("Could not reconnect: {e}");
+                                reconnect.pending = None;
+                                reconnect.next_attempt = now + 1.0;
                             }
-                            // ignore Empty (just means we haven't got anything to process
                             Err(std::sync::mpsc::TryRecvError::Empty) => {}
                             Err(std::sync::mpsc::TryRecvError::Disconnected) => {
-                                // todo: display error message to user
-                                error!("Disconnected from server");
-                                connected = false;
-                                scene = Scene::MainMenu;
+                                reconnect.pending = None;
                             }
                         }
-                        let player_move = render_game_state(
-                            &mut game_state,
-                            (mouse_x, mouse_y),
-                            &mut player_state,
-                            connected,
+                    } else if connection.is_some() {
+                        // waiting for the server to answer our resume request
+                    } else if now - reconnect.started > SEAT_GRACE_PERIOD_SECS as f64 {
+                        error!("Could not reconnect before our seat was released");
+                        player_state.playing_as = None;
+                        connection_error = ConnectionErrorState::new(
+                            format!("Could not reconnect to {} before your seat was given up", reconnect.addr),
+                            Some(Retry::Connect(reconnect.addr.clone())),
                         );
-                        match player_move {
-                            Some((from, to)) => {
-                                if let Some(team) = player_state.playing_as {
-                                    netsend_tx
-                                        .send(format!("move {team} {from} {to}\n"))
-                                        .expect(
-                                            "Could not send network command to networking thread",
-                                        );
-                                }
+                        scene = Scene::ConnectionError;
+                    } else if now >= reconnect.next_attempt {
+                        // connect off the render thread so the screen stays responsive
+                        reconnect.attempts += 1;
+                        reconnect.pending = Some(transport::connect_in_background(reconnect.addr.clone()));
+                    }
+                } else {
+                    scene = Scene::MainMenu;
+                }
+            }
+            Scene::WaitingForOpponent => {
+                render_waiting_for_opponent(
+                    &player_state,
+                    game_state.get_rules(),
+                    hosted_server.as_ref().map(|_| host_state.port),
+                );
+                let clocks = connection.as_ref().is_some_and(|c| c.supports(Capability::Clock));
+                if let (Some(msg), Some(connection)) =
+                    (render_game_controls(&game_state, &player_state, false, clocks), &mut connection)
+                {
+                    connection.send(msg);
+                }
+            }
+            Scene::RulesPicker => {
+                if let Some(rules) = render_rules_picker(&resources, &mut rules_menu_state) {
+                    match rules_menu_state.next {
+                        MenuOption::HostMultiplayer => {
+                            // local games are untimed, only hosted games get a clock
+                            rules_menu_state.rules = rules;
+                            scene = Scene::TimeControlPicker;
+                        }
+                        _ => {
+                            game_state.set_rules(rules);
+                            scene = Scene::InGame;
+                        }
+                    }
+                }
+            }
+            Scene::TimeControlPicker => {
+                // --host and retrying a failed host skip straight past the picker
+                let time_control = auto_host
+                    .take()
+                    .or_else(|| render_time_control_picker(&resources, &mut rules_menu_state));
+                if let Some(time_control) = time_control {
+                    let rules = rules_menu_state.rules;
+                    match start_server(rules, time_control) {
+                        Ok(host) => {
+                            host_state =
+                                HostState::new(get_time(), host.ready().port, rules, time_control);
+                            hosted_server = Some(host);
+                            scene = Scene::StartingServer;
+                        }
+                        Err(e) => {
+                            error!("Could not host a game: {e}");
+                            connection_error = ConnectionErrorState::new(
+                                format!("Could not start the server: {e}"),
+                                Some(Retry::Host(rules, time_control)),
+                            );
+                            scene = Scene::ConnectionError;
+                        }
+                    }
+                }
+            }
+            Scene::StartingServer => {
+                let now = get_time();
+                render_starting_server(&host_state);
+                let port = host_state.port;
+                if is_key_pressed(KeyCode::Escape) {
+                    info!("Cancelled hosting");
+                    hosted_server = None;
+                    scene = Scene::MainMenu;
+                } else if let Some(pending) = &host_state.pending {
+                    match pending.try_recv() {
+                        Ok(Ok(transport)) => {
+                            let addr = format!("127.0.0.1:{port}");
+                            info!("Connected to our own server at {addr}");
+                            connection = Some(Connection::new(transport, addr, now));
+                            host_state.pending = None;
+                            scene = Scene::InGame;
+                        }
+                        Ok(Err(e)) if host_state.attempts >= SERVER_CONNECT_ATTEMPTS => {
+                            error!("Could not connect to our own server: {e}");
+                            hosted_server = None;
+                            connection_error = ConnectionErrorState::new(
+                                format!("Could not connect to the server we started: {e}"),
+                                Some(Retry::Host(host_state.rules, host_state.time_control)),
+                            );
+                            scene = Scene::ConnectionError;
+                        }
+                        Ok(Err(e)) => {
+                            info!("Could not connect to our own server yet: {e}");
+                            host_state.pending = None;
+                            // 0.1s, 0.2s, 0.4s and so on
+                            host_state.next_attempt =
+                                now + 0.1 * 2f64.powi(host_state.attempts as i32 - 1);
+                        }
+                        Err(std::sync::mpsc::TryRecvError::Empty) => {}
+                        Err(std::sync::mpsc::TryRecvError::Disconnected) => {
+                            host_state.pending = None;
+                        }
+                    }
+                } else if now >= host_state.next_attempt {
+                    host_state.attempts += 1;
+                    host_state.pending =
+                        Some(transport::connect_in_background(format!("127.0.0.1:{port}")));
+                }
+            }
+            Scene::ConnectionError => {
+                let choice = render_connection_error(&resources, &mut connection_error);
+                // whatever the player picks, anything left of the old session goes; a retry starts
+                // a new one
+                if choice.is_some() {
+                    if let Some(connection) = connection.take() {
+                        connection.leave();
+                    }
+                }
+                match choice {
+                    Some(ErrorChoice::Retry) => match connection_error.retry.take() {
+                        Some(Retry::Connect(addr)) => {
+                            info!("Retrying connection to {addr}");
+                            enter_ip_state.input.text = addr.clone();
+                            auto_connect = Some(addr);
+                            scene = Scene::EnterIp;
+                        }
+                        Some(Retry::Host(rules, time_control)) => {
+                            info!("Retrying hosting");
+                            rules_menu_state.rules = rules;
+                            auto_host = Some(time_control);
+                            scene = Scene::TimeControlPicker;
+                        }
+                        None => scene = Scene::MainMenu,
+                    },
+                    Some(ErrorChoice::Back) => {
+                        // the server may have had us playing on a board of its own
+                        game_state.set_layout(local_layout.clone());
+                        player_state.new_game();
+                        hosted_server = None;
+                        scene = Scene::MainMenu;
+                    }
+                    None => {}
+                }
+            }
+            Scene::BrowseLan => {
+                let chosen = lan_browser_state
+                    .as_mut()
+                    .and_then(|state| render_lan_browser(&resources, state));
+                if let Some(addr) = chosen {
+                    info!("Joining LAN game at {addr}");
+                    // connected to the same way as an address typed in by hand
+                    lan_browser_state = None;
+                    auto_connect = Some(addr.to_string());
+                    scene = Scene::EnterIp;
+                } else if is_key_pressed(KeyCode::Escape) {
+                    lan_browser_state = None;
+                    scene = Scene::MainMenu;
+                }
+            }
+            Scene::Replay => {
+                if let Some(replay) = &mut replay_state {
+                    // rebuilt from scratch on every step, backwards steps included
+                    if render_replay_controls(replay) {
+                        game_state.set_layout(replay.layout.clone());
+                        game_state.set_rules(replay.rules);
+                        for (team, from, to) in &replay.moves[..replay.shown] {
+                            game_state.make_move(*team, *from, *to);
+                        }
+                    }
+                    // passed as connected so that clicking the game over box doesn't reset the board
+                    render_game_state(&mut game_state, (mouse_x, mouse_y), &mut player_state, true);
+                } else {
+                    scene = Scene::MainMenu;
+                }
+            }
+            Scene::InGame => {
+                if let Some(connection) = &mut connection {
+                    if player_state.playing_as.is_some() || player_state.spectating {
+                        let player_move =
+                            render_game_state(&mut game_state, (mouse_x, mouse_y), &mut player_state, true);
+                        if let Some((from, to)) = player_move {
+                            if let Some(team) = player_state.playing_as {
+                                player_state.pending_move = Some((from, to));
+                                connection.send(ClientMessage::Move(team, from, to));
+                            }
+                        }
+                        let clocks = connection.supports(Capability::Clock);
+                        if let Some(msg) = render_game_controls(&game_state, &player_state, true, clocks) {
+                            connection.send(msg);
+                        }
+                        if connection.supports(Capability::Chat) {
+                            if let Some(text) = render_chat(&mut chat_state) {
+                                connection.send(ClientMessage::Chat(text));
                             }
-                            None => {}
                         }
                     } else {
                         scene = Scene::TeamPicker;
                     }
                 } else {
                     let player_move =
-                        render_game_state(&mut game_state, (mouse_x, mouse_y), &mut player_state, connected);
-                    match player_move {
-                        Some((from, to)) => {
-                            game_state.make_move(game_state.turn, from, to);
-                        }
-                        None => {}
+                        render_game_state(&mut game_state, (mouse_x, mouse_y), &mut player_state, false);
+                    if let Some((from, to)) = player_move {
+                        game_state.make_move(game_state.turn, from, to);
                     }
                 }
             }
@@ -718,41 +2416,24 @@ async fn main() {
                         MenuOption::Quit => {
                             break;
                         }
-                        MenuOption::LocalGame => {
-                            game_state.reset(); // shouldn't be required, but just in case
-                            scene = Scene::InGame;
-                        }
-                        MenuOption::HostMultiplayer => {
-                            if let Ok(mut path_to_executable) = current_exe() {
-                                path_to_executable.pop();
-                                if cfg!(target_os = "windows") {
-                                    path_to_executable.push("guardsd.exe");
-                                } else {
-                                    path_to_executable.push("guardsd");
-                                }
-                                _child = Some(ChildGuard(
-                                    Command::new(path_to_executable)
-                                        .spawn()
-                                        .expect("Could not run server executable"),
-                                ));
-                            }
-                            // wait a moment for the server to start listening (todo: can this be
-                            // implemented differently? maybe give the user some feedback that this
-                            // is what we're doing?)
-                            sleep(time::Duration::from_millis(500));
-
-                            let mut constr = String::new();
-                            let _ = write!(constr, "127.0.0.1:{}", PORT);
-                            let stream = TcpStream::connect(constr.as_str())
-                                .expect("Failed to connect to server");
-                            let _ = netconn_tx
-                                .send(stream)
-                                .expect("Could not send connection to networking thread");
-                            // todo: check if connection actually succeeded?
-                            connected = true;
-                            scene = Scene::InGame;
+                        MenuOption::LocalGame | MenuOption::HostMultiplayer => {
+                            player_state.spectating = false;
+                            rules_menu_state.next = option;
+                            scene = Scene::RulesPicker;
                         }
                         MenuOption::JoinMultiplayer => {
+                            player_state.spectating = false;
+                            scene = Scene::EnterIp;
+                        }
+                        MenuOption::BrowseLan => {
+                            player_state.spectating = false;
+                            // let go of the port before listening on it again
+                            drop(lan_browser_state.take());
+                            lan_browser_state = Some(LanBrowserState::new());
+                            scene = Scene::BrowseLan;
+                        }
+                        MenuOption::WatchMultiplayer => {
+                            player_state.spectating = true;
                             scene = Scene::EnterIp;
                         }
                     }
@@ -760,6 +2441,11 @@ async fn main() {
             }
         }
 
+        if let Some(latency) = connection.as_ref().and_then(|connection| connection.heartbeat.latency) {
+            render_latency(latency);
+        }
+        render_status(&mut status_state, get_time());
+
         next_frame().await
     }
 }
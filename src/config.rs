@@ -0,0 +1,198 @@
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use clap::{Parser, ValueEnum};
+use serde::Deserialize;
+use tracing::Level;
+
+use crate::board::{parse_layout, DEFAULT_LAYOUT};
+use crate::game::{RuleSet, TimeControl};
+use crate::net::{is_valid_server_name, MAX_SERVER_NAME_LEN, PORT, WEBSOCKET_PORT};
+
+const DEFAULT_MAX_ROOMS: usize = 100;
+const DEFAULT_NAME: &str = "guardsd";
+
+/// Hosts Guards! games for players to join over the network.
+#[derive(Parser, Debug)]
+#[command(version)]
+pub struct Args {
+    /// Read settings from a TOML file; options given here take precedence over it
+    #[arg(short, long)]
+    pub config: Option<PathBuf>,
+    /// Address to listen on [default: 0.0.0.0]
+    #[arg(long)]
+    pub bind: Option<IpAddr>,
+    /// Port for the line protocol [default: 34865]
+    #[arg(short, long)]
+    pub port: Option<u16>,
+    /// Port for WebSocket connections from browsers [default: 34866]
+    #[arg(long)]
+    pub websocket_port: Option<u16>,
+    /// One of trace, debug, info, warn or error [default: info]
+    #[arg(long)]
+    pub log_level: Option<Level>,
+    /// How log lines are written [default: full]
+    #[arg(long, value_enum)]
+    pub log_format: Option<LogFormat>,
+    /// Play on the board layout in this file instead of the built-in one
+    #[arg(long)]
+    pub board: Option<PathBuf>,
+    /// Rules preset new rooms start with: classic, forum or sentries [default: classic]
+    #[arg(long, value_parser = parse_rules)]
+    pub rules: Option<RuleSet>,
+    /// Time control new rooms start with, as <base>+<increment> in seconds, or none [default: none]
+    #[arg(long)]
+    pub time_control: Option<String>,
+    /// Most rooms that can be open at once, counting the main room [default: 100]
+    #[arg(long)]
+    pub max_rooms: Option<usize>,
+    /// What the server is called in players' lists of LAN games [default: guardsd]
+    #[arg(long)]
+    pub name: Option<String>,
+    /// Where to announce the server for LAN discovery [default: 255.255.255.255]
+    #[arg(long, value_name = "IP", conflicts_with = "no_announce")]
+    pub announce_to: Option<IpAddr>,
+    /// Don't announce the server on the local network at all
+    #[arg(long)]
+    pub no_announce: bool,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Full,
+    Compact,
+    // one JSON object per line, for log collectors
+    Json,
+}
+
+// the same settings as the command line, all optional
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct ConfigFile {
+    bind: Option<IpAddr>,
+    port: Option<u16>,
+    websocket_port: Option<u16>,
+    log_level: Option<String>,
+    log_format: Option<LogFormat>,
+    board: Option<PathBuf>,
+    rules: Option<RuleSet>,
+    // "none" for untimed games
+    time_control: Option<String>,
+    max_rooms: Option<usize>,
+    name: Option<String>,
+    announce_to: Option<IpAddr>,
+    // false to not announce the server at all
+    announce: Option<bool>,
+}
+
+// everything guardsd needs to know to start up, with defaults filled in
+pub struct Settings {
+    pub bind: IpAddr,
+    pub port: u16,
+    pub websocket_port: u16,
+    pub log_level: Level,
+    pub log_format: LogFormat,
+    pub layout: String,
+    pub rules: RuleSet,
+    pub time_control: Option<TimeControl>,
+    pub max_rooms: usize,
+    pub name: String,
+    // None if the server shouldn't be announced
+    pub announce_to: Option<IpAddr>,
+}
+
+fn parse_rules(s: &str) -> Result<RuleSet, String> {
+    RuleSet::from_str(s).map_err(|_| format!("unknown rules preset {s}"))
+}
+
+fn parse_time_control(s: &str) -> Result<Option<TimeControl>, String> {
+    if s == "none" {
+        return Ok(None);
+    }
+    TimeControl::from_str(s)
+        .map(Some)
+        .map_err(|_| format!("expected <base>+<increment> in seconds or none, not {s}"))
+}
+
+fn read_layout(path: &Path) -> Result<String, String> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("Could not read board {}: {e}", path.display()))?;
+    parse_layout(&text).map_err(|e| format!("Invalid board {}: {e}", path.display()))
+}
+
+impl Settings {
+    pub fn load(args: Args) -> Result<Self, String> {
+        let file = match &args.config {
+            Some(path) => {
+                let text = fs::read_to_string(path)
+                    .map_err(|e| format!("Could not read config {}: {e}", path.display()))?;
+                let mut file: ConfigFile = toml::from_str(&text)
+                    .map_err(|e| format!("Invalid config {}: {e}", path.display()))?;
+                // a board named in the config file is relative to the file, not to wherever
+                // guardsd happens to be started from
+                if let (Some(board), Some(dir)) = (&file.board, path.parent()) {
+                    file.board = Some(dir.join(board));
+                }
+                file
+            }
+            None => ConfigFile::default(),
+        };
+        let log_level = match (args.log_level, file.log_level) {
+            (Some(level), _) => level,
+            (None, Some(level)) => {
+                Level::from_str(&level).map_err(|_| format!("Invalid log level {level}"))?
+            }
+            (None, None) => Level::INFO,
+        };
+        let time_control = match args.time_control.or(file.time_control) {
+            Some(time_control) => parse_time_control(&time_control)?,
+            None => None,
+        };
+        let layout = match args.board.or(file.board) {
+            Some(path) => read_layout(&path)?,
+            None => DEFAULT_LAYOUT.to_string(),
+        };
+        let max_rooms = args.max_rooms.or(file.max_rooms).unwrap_or(DEFAULT_MAX_ROOMS);
+        if max_rooms == 0 {
+            return Err("max-rooms must leave space for at least the main room".to_string());
+        }
+        let name = args
+            .name
+            .or(file.name)
+            .unwrap_or_else(|| DEFAULT_NAME.to_string());
+        if !is_valid_server_name(&name) {
+            return Err(format!(
+                "name must be at most {MAX_SERVER_NAME_LEN} printable characters, not {name:?}"
+            ));
+        }
+        // asking for announcements on the command line overrides turning them off in the file
+        let announce =
+            !args.no_announce && (args.announce_to.is_some() || file.announce.unwrap_or(true));
+        let announce_to = args
+            .announce_to
+            .or(file.announce_to)
+            .unwrap_or(IpAddr::V4(Ipv4Addr::BROADCAST));
+        Ok(Self {
+            bind: args
+                .bind
+                .or(file.bind)
+                .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+            port: args.port.or(file.port).unwrap_or(PORT),
+            websocket_port: args
+                .websocket_port
+                .or(file.websocket_port)
+                .unwrap_or(WEBSOCKET_PORT),
+            log_level,
+            log_format: args.log_format.or(file.log_format).unwrap_or(LogFormat::Full),
+            layout,
+            rules: args.rules.or(file.rules).unwrap_or_default(),
+            time_control,
+            max_rooms,
+            name,
+            announce_to: announce.then_some(announce_to),
+        })
+    }
+}
@@ -0,0 +1,211 @@
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use tracing::{debug, warn};
+
+use crate::net::{Announcement, ANNOUNCE_INTERVAL_SECS, DISCOVERY_PORT};
+
+// a server that hasn't announced itself for this long is assumed to have gone away
+const LAN_GAME_TIMEOUT_SECS: u64 = ANNOUNCE_INTERVAL_SECS * 3;
+// comfortably bigger than any announcement
+const MAX_ANNOUNCEMENT_LEN: usize = 512;
+
+// sends a server's announcements; usually to the broadcast address, but any address will do, which
+// is handy for trying it out on loopback
+#[allow(dead_code)]
+pub struct Announcer {
+    socket: UdpSocket,
+    to: SocketAddr,
+    last_sent: Option<Instant>,
+}
+
+#[allow(dead_code)]
+impl Announcer {
+    pub fn new(to: IpAddr) -> io::Result<Self> {
+        let unspecified = match to {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        };
+        let socket = UdpSocket::bind((unspecified, 0))?;
+        socket.set_broadcast(true)?;
+        Ok(Self {
+            socket,
+            to: SocketAddr::new(to, DISCOVERY_PORT),
+            last_sent: None,
+        })
+    }
+
+    pub fn due(&self, now: Instant) -> bool {
+        self.last_sent.is_none_or(|last_sent| {
+            now.duration_since(last_sent) >= Duration::from_secs(ANNOUNCE_INTERVAL_SECS)
+        })
+    }
+
+    pub fn announce(&mut self, announcement: &Announcement, now: Instant) {
+        // only complain the first time, rather than every few seconds for as long as we're running
+        match self.socket.send_to(announcement.to_string().as_bytes(), self.to) {
+            Ok(_) => debug!("Announced {announcement} to {}", self.to),
+            Err(e) if self.last_sent.is_none() => warn!("Could not announce server to {}: {e}", self.to),
+            Err(_) => {}
+        }
+        self.last_sent = Some(now);
+    }
+}
+
+// every client on the machine listens on the same port, so it has to be shared; announcements are
+// broadcast, which reaches all of them
+#[cfg(not(target_arch = "wasm32"))]
+fn bind_shared(port: u16) -> io::Result<UdpSocket> {
+    use socket2::{Domain, Protocol, Socket, Type};
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    // linux shares the port on SO_REUSEADDR alone, and SO_REUSEPORT there would spread datagrams
+    // sent straight to the port between the sockets instead of handing them to the newest
+    #[cfg(all(unix, not(any(target_os = "linux", target_os = "android"))))]
+    socket.set_reuse_port(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)).into())?;
+    Ok(socket.into())
+}
+
+// there's no browsing from the browser client anyway
+#[cfg(target_arch = "wasm32")]
+fn bind_shared(port: u16) -> io::Result<UdpSocket> {
+    UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port))
+}
+
+// a server heard announcing itself on the local network
+#[allow(dead_code)]
+pub struct LanGame {
+    // where the announcement came from, with the port to connect to
+    pub addr: SocketAddr,
+    pub announcement: Announcement,
+    last_heard: Instant,
+}
+
+// listens for announcements, and keeps track of the servers that are still making them
+#[allow(dead_code)]
+pub struct LanBrowser {
+    socket: UdpSocket,
+    games: Vec<LanGame>,
+}
+
+#[allow(dead_code)]
+impl LanBrowser {
+    pub fn new() -> io::Result<Self> {
+        let socket = bind_shared(DISCOVERY_PORT)?;
+        // polled from the render loop, so it can't ever block
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            socket,
+            games: Vec::new(),
+        })
+    }
+
+    // takes in whatever has arrived since last time, and forgets servers that have gone quiet
+    pub fn poll(&mut self, now: Instant) {
+        let mut buf = [0; MAX_ANNOUNCEMENT_LEN];
+        loop {
+            let (len, from) = match self.socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    warn!("Could not receive announcement: {e}");
+                    break;
+                }
+            };
+            let Some(announcement) = std::str::from_utf8(&buf[..len])
+                .ok()
+                .and_then(|text| text.parse::<Announcement>().ok())
+            else {
+                debug!("Ignoring datagram from {from} that isn't an announcement");
+                continue;
+            };
+            let addr = SocketAddr::new(from.ip(), announcement.port);
+            match self.games.iter_mut().find(|game| game.addr == addr) {
+                Some(game) => {
+                    game.announcement = announcement;
+                    game.last_heard = now;
+                }
+                None => {
+                    debug!("Found {} at {addr}", announcement.name);
+                    self.games.push(LanGame {
+                        addr,
+                        announcement,
+                        last_heard: now,
+                    });
+                }
+            }
+        }
+        self.games.retain(|game| {
+            now.duration_since(game.last_heard) < Duration::from_secs(LAN_GAME_TIMEOUT_SECS)
+        });
+    }
+
+    pub fn games(&self) -> &[LanGame] {
+        &self.games
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::PROTOCOL_VERSION;
+
+    // any of these found elsewhere on the network, or announced by a server running alongside the
+    // tests, are left out
+    fn loopback_games(browser: &LanBrowser) -> Vec<&LanGame> {
+        browser
+            .games()
+            .iter()
+            .filter(|game| game.announcement.name == "loopback")
+            .collect()
+    }
+
+    #[test]
+    fn browser_hears_announcements_on_loopback() {
+        // like a client that's already browsing on the same machine
+        let _browsing = LanBrowser::new().expect("Could not listen for announcements");
+        let mut browser = LanBrowser::new().expect("Could not share the discovery port");
+        let mut announcer = Announcer::new(IpAddr::V4(Ipv4Addr::LOCALHOST)).unwrap();
+        let announcement = Announcement {
+            protocol_version: PROTOCOL_VERSION,
+            port: 4321,
+            websocket_port: 4322,
+            rooms: 2,
+            open_seats: 3,
+            name: "loopback".to_string(),
+        };
+        let start = Instant::now();
+        assert!(announcer.due(start));
+        announcer.announce(&announcement, start);
+        assert!(!announcer.due(start));
+        assert!(announcer.due(start + Duration::from_secs(ANNOUNCE_INTERVAL_SECS)));
+
+        // the datagram has to make its way through the kernel, even on loopback
+        while loopback_games(&browser).is_empty() && start.elapsed() < Duration::from_secs(5) {
+            std::thread::sleep(Duration::from_millis(10));
+            browser.poll(start);
+        }
+        let [game] = loopback_games(&browser)[..] else {
+            panic!("Expected to hear exactly one server");
+        };
+        assert_eq!(game.addr, SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 4321));
+        assert_eq!(game.announcement, announcement);
+
+        // announcing again refreshes the listing rather than adding to it
+        let later = start + Duration::from_secs(ANNOUNCE_INTERVAL_SECS);
+        announcer.announce(&Announcement { open_seats: 1, ..announcement }, later);
+        let updated = |browser: &LanBrowser| loopback_games(browser)[0].announcement.open_seats == 1;
+        while !updated(&browser) && start.elapsed() < Duration::from_secs(5) {
+            std::thread::sleep(Duration::from_millis(10));
+            browser.poll(later);
+        }
+        assert_eq!(loopback_games(&browser).len(), 1);
+        assert!(updated(&browser));
+
+        // and a server that goes quiet is forgotten
+        browser.poll(later + Duration::from_secs(LAN_GAME_TIMEOUT_SECS));
+        assert!(loopback_games(&browser).is_empty());
+    }
+}
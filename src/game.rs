@@ -1,10 +1,13 @@
 use macroquad::prelude::*;
-use crate::board::{Board, BoardSquareCoords, Square, SquareEdge, SquareOccupant};
+use crate::board::{Board, BoardSquareCoords, Square, SquareEdge, SquareOccupant, DEFAULT_LAYOUT};
 use std::fmt;
-use std::cell::Cell;
+use std::cell::{Cell, RefCell};
 use std::str::FromStr;
+use std::time::Duration;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
-#[derive(PartialEq, Clone, Copy, Debug)]
+#[derive(PartialEq, Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Team {
     Purple,
     White,
@@ -14,8 +17,7 @@ pub enum Team {
 impl FromStr for Team {
     type Err = ();
     fn from_str(str: &str) -> Result<Self, Self::Err> {
-        let str_ref = str.as_ref();
-        match str_ref {
+        match str {
             "purple" | "PURPLE" | "Purple" => Ok(Team::Purple),
             "white"  | "WHITE" | "White" => Ok(Team::White),
             _ => Err(()),
@@ -23,6 +25,14 @@ impl FromStr for Team {
     }
 }
 
+// only the teams players can be on, the same as the text protocol
+impl<'de> Deserialize<'de> for Team {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        Team::from_str(&name).map_err(|_| serde::de::Error::custom(format!("Unrecognised team {name}")))
+    }
+}
+
 #[allow(dead_code)]
 impl Team {
     pub fn opposite(&self) -> Self {
@@ -51,19 +61,247 @@ impl fmt::Display for Team {
     }
 }
 
+/// Which team the (initially neutral) guards side with when the first murder happens.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum GuardAllegiance {
+    Victim,
+    Murderer,
+}
+
+/// The rule choices that the README is unsure about, made explicit so that variants can be played
+/// and negotiated over the network.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub struct RuleSet {
+    pub guards_join: GuardAllegiance,
+    // whether the magistrate counts as a wall when checking if a citizen has been surrounded
+    pub magistrate_can_kill: bool,
+    // whether the team controlling the guards may move them, or only use them where they stand
+    pub guards_can_move: bool,
+}
+
+#[allow(dead_code)]
+impl RuleSet {
+    pub const CLASSIC: RuleSet = RuleSet {
+        guards_join: GuardAllegiance::Victim,
+        magistrate_can_kill: true,
+        guards_can_move: true,
+    };
+    // the magistrate only blocks movement and the guards side with whoever struck first
+    pub const FORUM: RuleSet = RuleSet {
+        guards_join: GuardAllegiance::Murderer,
+        magistrate_can_kill: false,
+        guards_can_move: true,
+    };
+    // guards stay at their posts for the whole game
+    pub const SENTRIES: RuleSet = RuleSet {
+        guards_join: GuardAllegiance::Victim,
+        magistrate_can_kill: true,
+        guards_can_move: false,
+    };
+
+    pub const PRESETS: [(&'static str, RuleSet); 3] = [
+        ("classic", Self::CLASSIC),
+        ("forum", Self::FORUM),
+        ("sentries", Self::SENTRIES),
+    ];
+
+    pub fn preset_name(&self) -> Option<&'static str> {
+        Self::PRESETS
+            .iter()
+            .find(|(_, rules)| rules == self)
+            .map(|(name, _)| *name)
+    }
+}
+
+impl Default for RuleSet {
+    fn default() -> Self {
+        Self::CLASSIC
+    }
+}
+
+impl FromStr for RuleSet {
+    type Err = ();
+    fn from_str(str: &str) -> Result<Self, Self::Err> {
+        Self::PRESETS
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(str))
+            .map(|(_, rules)| *rules)
+            .ok_or(())
+    }
+}
+
+impl fmt::Display for RuleSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.preset_name().unwrap_or("custom"))
+    }
+}
+
+// rules go over the wire by preset name, whichever encoding is in use
+impl Serialize for RuleSet {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for RuleSet {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        RuleSet::from_str(&name)
+            .map_err(|_| serde::de::Error::custom(format!("Unrecognised rules {name}")))
+    }
+}
+
+/// How long each player has for the whole game, and how much time they get back after each of
+/// their moves. Written as base+increment in seconds, e.g. 300+5.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub struct TimeControl {
+    pub base: Duration,
+    pub increment: Duration,
+}
+
 #[allow(dead_code)]
+impl TimeControl {
+    pub const fn new(base_secs: u64, increment_secs: u64) -> Self {
+        Self {
+            base: Duration::from_secs(base_secs),
+            increment: Duration::from_secs(increment_secs),
+        }
+    }
+
+    // offered when hosting a game; None is an untimed game
+    pub const PRESETS: [(&'static str, Option<TimeControl>); 4] = [
+        ("Untimed", None),
+        ("3+2", Some(Self::new(180, 2))),
+        ("5+3", Some(Self::new(300, 3))),
+        ("15+10", Some(Self::new(900, 10))),
+    ];
+}
+
+impl FromStr for TimeControl {
+    type Err = ();
+    fn from_str(str: &str) -> Result<Self, Self::Err> {
+        let (base, increment) = str.split_once('+').ok_or(())?;
+        let base = base.parse::<u64>().map_err(|_| ())?;
+        let increment = increment.parse::<u64>().map_err(|_| ())?;
+        if base == 0 {
+            return Err(());
+        }
+        Ok(Self::new(base, increment))
+    }
+}
+
+impl fmt::Display for TimeControl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}+{}", self.base.as_secs(), self.increment.as_secs())
+    }
+}
+
+impl Serialize for TimeControl {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for TimeControl {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        TimeControl::from_str(&s)
+            .map_err(|_| serde::de::Error::custom(format!("Invalid time control {s}")))
+    }
+}
+
+#[allow(dead_code)]
+#[derive(PartialEq, Clone, Copy, Debug, Serialize, Deserialize)]
 pub enum WinState {
+    #[serde(rename = "purple")]
     PurpleWin,
+    #[serde(rename = "white")]
     WhiteWin,
+    #[serde(rename = "draw")]
     Draw
 }
 
+#[allow(dead_code)]
+impl WinState {
+    pub fn won_by(team: Team) -> Self {
+        match team {
+            Team::Purple => Self::PurpleWin,
+            Team::White => Self::WhiteWin,
+            Team::Neutral => panic!("Neutral team cannot win, this is a programming error"),
+        }
+    }
+}
+
+impl FromStr for WinState {
+    type Err = ();
+    fn from_str(str: &str) -> Result<Self, Self::Err> {
+        match str {
+            "purple" => Ok(Self::PurpleWin),
+            "white" => Ok(Self::WhiteWin),
+            "draw" => Ok(Self::Draw),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for WinState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::PurpleWin => write!(f, "purple"),
+            Self::WhiteWin => write!(f, "white"),
+            Self::Draw => write!(f, "draw"),
+        }
+    }
+}
+
+/// How a game came to an end.
+#[allow(dead_code)]
+#[derive(PartialEq, Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EndReason {
+    // one side (or both) ran out of citizens
+    Murder,
+    Resignation,
+    // both players agreed to a draw
+    Agreement,
+    // the loser's clock ran out
+    Timeout,
+}
+
+impl FromStr for EndReason {
+    type Err = ();
+    fn from_str(str: &str) -> Result<Self, Self::Err> {
+        match str {
+            "murder" => Ok(Self::Murder),
+            "resignation" => Ok(Self::Resignation),
+            "agreement" => Ok(Self::Agreement),
+            "timeout" => Ok(Self::Timeout),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for EndReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Murder => write!(f, "murder"),
+            Self::Resignation => write!(f, "resignation"),
+            Self::Agreement => write!(f, "agreement"),
+            Self::Timeout => write!(f, "timeout"),
+        }
+    }
+}
+
 #[allow(dead_code)]
 pub struct GameState {
     pub turn: Team,
     board: Board,
     pub game_over: Option<WinState>,
     pub murder_happened: Cell<bool>,
+    rules: RuleSet,
+    guard_controller: Team,
+    // the walls of the board, as read by Board::from_layout
+    layout: String,
 }
 
 #[allow(dead_code)]
@@ -107,19 +345,39 @@ fn is_path_clear(board: &Board, from: BoardSquareCoords, to: BoardSquareCoords)
 
 #[allow(dead_code)]
 impl GameState {
-    pub fn new() -> GameState {
+    pub fn new(rules: RuleSet) -> GameState {
         GameState {
             turn: Team::White,
             game_over: None,
             board: Board::new(),
             murder_happened: Cell::new(false),
+            rules,
+            guard_controller: Team::Neutral,
+            layout: DEFAULT_LAYOUT.to_string(),
         }
     }
     pub fn reset(&mut self) {
         self.turn = Team::White;
         self.game_over = None;
-        self.board = Board::new();
+        self.board = Board::from_layout(&self.layout);
         self.murder_happened.set(false);
+        self.guard_controller = Team::Neutral;
+    }
+    // changing the rules mid-game makes no sense, so this also starts a fresh game
+    pub fn set_rules(&mut self, rules: RuleSet) {
+        self.rules = rules;
+        self.reset();
+    }
+    // like the rules, a different board means a fresh game
+    pub fn set_layout(&mut self, layout: String) {
+        self.layout = layout;
+        self.reset();
+    }
+    pub fn get_layout(&self) -> &str {
+        &self.layout
+    }
+    pub fn get_rules(&self) -> &RuleSet {
+        &self.rules
     }
     pub fn get_board(&self) -> &Board {
         &self.board
@@ -170,42 +428,23 @@ impl GameState {
                     to_square.occupant =
                         std::mem::replace(&mut from_square.occupant, SquareOccupant::Empty);
                     self.board.squares.insert(from, from_square);
-                    let murder_victim: Cell<Option<(BoardSquareCoords, Team)>> = Cell::new(None);
-
+                    // only the pieces next to the one that moved can have been newly surrounded
+                    let victims: RefCell<Vec<BoardSquareCoords>> = RefCell::new(Vec::new());
+                    let victim_team = self.turn.opposite();
                     self.check_neighbours(to, |neighbour, position, _| {
-                        match neighbour.occupant {
-                            SquareOccupant::Guard(_) |
-                            SquareOccupant::Magistrate(_) |
-                            SquareOccupant::Empty => {},
-                            SquareOccupant::Citizen(team) => {
-                                if team != self.turn {
-                                    let murdered = Cell::new(true);
-                                    self.check_neighbours(*position, |neighbour, _, dir| {
-                                        let opposite_dir = dir.get_opposite();
-                                        if !neighbour.wall.contains(&opposite_dir) &&
-                                            match neighbour.occupant {
-                                                SquareOccupant::Empty => true,
-                                                SquareOccupant::Magistrate(_) => false,
-                                                SquareOccupant::Guard(team) |
-                                                SquareOccupant::Citizen(team) => {
-                                                    team != self.turn
-                                                }
-                                            }
-                                        {
-                                            murdered.set(false);
-                                        }
-                                    });
-                                    if murdered.get() {
-                                        murder_victim.set(Some((position.clone(), team)));
-                                    }
-                                }
-                            }
+                        if neighbour.occupant == SquareOccupant::Citizen(victim_team)
+                            && self.is_enclosed(*position)
+                        {
+                            victims.borrow_mut().push(*position);
                         }
                     });
-                    if let Some((victim_location, victim_team)) = murder_victim.get() {
+                    let victims = victims.into_inner();
+                    if !victims.is_empty() {
                         self.murder_happened.set(true);
-                        if let Some(square) = self.board.squares.get_mut(&victim_location) {
-                            square.occupant = SquareOccupant::Empty;
+                        for victim_location in victims {
+                            if let Some(square) = self.board.squares.get_mut(&victim_location) {
+                                square.occupant = SquareOccupant::Empty;
+                            }
                         }
                         self.flip_guards(victim_team);
                     }
@@ -216,24 +455,63 @@ impl GameState {
         } 
         false
     }
+    // whether `occupant` counts towards surrounding a citizen of the `victim` team
+    fn encloses(&self, occupant: SquareOccupant, victim: Team) -> bool {
+        match occupant {
+            SquareOccupant::Empty => false,
+            // the magistrate is a neutral blocking token, whoever currently controls him
+            SquareOccupant::Magistrate(_) => self.rules.magistrate_can_kill,
+            // guards can only be used to murder the team that does not currently control them
+            // (neutral guards can be used against either team)
+            SquareOccupant::Guard(_) => self.get_guard_controller() != victim,
+            // a citizen's own team mates always give it an escape route
+            SquareOccupant::Citizen(team) => team != victim,
+        }
+    }
+    // a citizen is enclosed (and so murdered) when every side is either a wall, the edge of the
+    // board, or a piece that can be used against its team
+    pub fn is_enclosed(&self, pos: BoardSquareCoords) -> bool {
+        let Some(square) = self.board.squares.get(&pos) else {
+            return false;
+        };
+        let SquareOccupant::Citizen(victim) = square.occupant else {
+            return false;
+        };
+        let enclosed = Cell::new(true);
+        self.check_neighbours(pos, |neighbour, _, dir| {
+            let walled = square.wall.contains(dir) || neighbour.wall.contains(&dir.get_opposite());
+            if !walled && !self.encloses(neighbour.occupant, victim) {
+                enclosed.set(false);
+            }
+        });
+        enclosed.get()
+    }
+    pub fn get_guard_controller(&self) -> Team {
+        self.guard_controller
+    }
+    // the magistrate is controlled by the player who doesn't control the guards, so he stays
+    // neutral for exactly as long as they do
+    pub fn get_magistrate_controller(&self) -> Team {
+        match self.guard_controller {
+            Team::Neutral => Team::Neutral,
+            team => team.opposite(),
+        }
+    }
     pub fn flip_guards(&mut self, victim_team: Team) {
-        for (_, square) in &mut self.board.squares {
+        self.guard_controller = match (self.guard_controller, self.rules.guards_join) {
+            (Team::Neutral, GuardAllegiance::Victim) => victim_team,
+            (Team::Neutral, GuardAllegiance::Murderer) => victim_team.opposite(),
+            (team, _) => team.opposite(),
+        };
+        let guard_team = self.guard_controller;
+        let magistrate_team = self.get_magistrate_controller();
+        for square in self.board.squares.values_mut() {
             match square.occupant {
-                SquareOccupant::Guard(team) => {
-                    let new_team = match team {
-                        Team::Purple => Team::White,
-                        Team::White => Team::Purple,
-                        Team::Neutral => victim_team,
-                    };
-                    square.occupant = SquareOccupant::Guard(new_team);
+                SquareOccupant::Guard(_) => {
+                    square.occupant = SquareOccupant::Guard(guard_team);
                 },
-                SquareOccupant::Magistrate(team) => {
-                    let new_team = match team {
-                        Team::Purple => Team::White,
-                        Team::White => Team::Purple,
-                        Team::Neutral => victim_team.opposite(),
-                    };
-                    square.occupant = SquareOccupant::Magistrate(new_team);
+                SquareOccupant::Magistrate(_) => {
+                    square.occupant = SquareOccupant::Magistrate(magistrate_team);
                 },
                 _ => {},
             }
@@ -249,13 +527,18 @@ impl GameState {
         if let Some(from_square) = self.board.squares.get(&from) {
             match &from_square.occupant {
                 SquareOccupant::Empty => false,
-                SquareOccupant::Guard(team)
-                | SquareOccupant::Magistrate(team)
-                | SquareOccupant::Citizen(team) => {
-                    if *team == self.turn {
-                        if from.0 == to.0 || from.1 == to.1 {
-                            return is_path_clear(&self.board, from, to);
-                        }
+                SquareOccupant::Guard(_) if !self.rules.guards_can_move => false,
+                occupant => {
+                    // guard and magistrate allegiance is tracked by the game rather than trusted
+                    // from the board, so the two can never end up controlled by the same player
+                    let controller = match occupant {
+                        SquareOccupant::Guard(_) => self.get_guard_controller(),
+                        SquareOccupant::Magistrate(_) => self.get_magistrate_controller(),
+                        SquareOccupant::Citizen(team) => *team,
+                        SquareOccupant::Empty => Team::Neutral,
+                    };
+                    if controller == self.turn && (from.0 == to.0 || from.1 == to.1) {
+                        return is_path_clear(&self.board, from, to);
                     }
                     false
                 }
@@ -268,16 +551,13 @@ impl GameState {
         // evaluate win condition - all opponents are dead
         let mut purple_count = 0;
         let mut white_count = 0;
-        for (_, square) in &self.board.squares {
-            match square.occupant  {
-                SquareOccupant::Citizen(team) => {
-                    match team {
-                        Team::Purple => { purple_count += 1; }
-                        Team::White => {  white_count += 1; }
-                        _ => {}
-                    }
+        for square in self.board.squares.values() {
+            if let SquareOccupant::Citizen(team) = square.occupant {
+                match team {
+                    Team::Purple => { purple_count += 1; }
+                    Team::White => {  white_count += 1; }
+                    _ => {}
                 }
-                _ => {}
             }
         }
         if purple_count == 0 && white_count == 0 {
@@ -300,3 +580,406 @@ impl GameState {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::board::{BOARD_HEIGHT, BOARD_WIDTH};
+
+    const PURPLE: SquareOccupant = SquareOccupant::Citizen(Team::Purple);
+    const WHITE: SquareOccupant = SquareOccupant::Citizen(Team::White);
+
+    // an open board, apart from the walls given as (x, y, hex digit) in the same form as board.txt
+    fn layout(walls: &[(u32, u32, char)]) -> String {
+        (0..BOARD_HEIGHT as u32)
+            .map(|y| {
+                (0..BOARD_WIDTH as u32)
+                    .map(|x| {
+                        walls
+                            .iter()
+                            .find(|(wx, wy, _)| (*wx, *wy) == (x, y))
+                            .map_or('0', |(_, _, digit)| *digit)
+                    })
+                    .collect::<String>()
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    // a game with nothing on the board except the given pieces
+    fn game(rules: RuleSet, walls: &[(u32, u32, char)], pieces: &[(u32, u32, SquareOccupant)]) -> GameState {
+        let mut game = GameState::new(rules);
+        game.set_layout(layout(walls));
+        for square in game.board.squares.values_mut() {
+            square.occupant = SquareOccupant::Empty;
+        }
+        for (x, y, occupant) in pieces {
+            game.board
+                .squares
+                .get_mut(&BoardSquareCoords(*x, *y))
+                .expect("Piece placed off the board")
+                .occupant = *occupant;
+        }
+        game
+    }
+
+    struct EnclosureCase {
+        name: &'static str,
+        rules: RuleSet,
+        // the teams that have lost a citizen so far, in order, which decides who controls the guards
+        murdered: &'static [Team],
+        walls: &'static [(u32, u32, char)],
+        pieces: &'static [(u32, u32, SquareOccupant)],
+        victim: (u32, u32),
+        enclosed: bool,
+    }
+
+    // the victim is always a white citizen, in the middle of the board unless it says otherwise; the
+    // guards and the magistrate are placed neutral and change sides with the murders, as in a game
+    const ENCLOSURE_CASES: &[EnclosureCase] = &[
+        EnclosureCase {
+            name: "alone in the open",
+            rules: RuleSet::CLASSIC,
+            murdered: &[],
+            walls: &[],
+            pieces: &[(3, 4, WHITE)],
+            victim: (3, 4),
+            enclosed: false,
+        },
+        EnclosureCase {
+            name: "surrounded by enemy citizens",
+            rules: RuleSet::CLASSIC,
+            murdered: &[],
+            walls: &[],
+            pieces: &[(3, 4, WHITE), (3, 3, PURPLE), (4, 4, PURPLE), (3, 5, PURPLE), (2, 4, PURPLE)],
+            victim: (3, 4),
+            enclosed: true,
+        },
+        EnclosureCase {
+            name: "one side open",
+            rules: RuleSet::CLASSIC,
+            murdered: &[],
+            walls: &[],
+            pieces: &[(3, 4, WHITE), (3, 3, PURPLE), (4, 4, PURPLE), (3, 5, PURPLE)],
+            victim: (3, 4),
+            enclosed: false,
+        },
+        EnclosureCase {
+            name: "a team mate alongside",
+            rules: RuleSet::CLASSIC,
+            murdered: &[],
+            walls: &[],
+            pieces: &[(3, 4, WHITE), (3, 3, PURPLE), (4, 4, PURPLE), (3, 5, PURPLE), (2, 4, WHITE)],
+            victim: (3, 4),
+            enclosed: false,
+        },
+        EnclosureCase {
+            name: "walls on three sides of its own square",
+            rules: RuleSet::CLASSIC,
+            murdered: &[],
+            // north, east and south
+            walls: &[(3, 4, 'E')],
+            pieces: &[(3, 4, WHITE), (2, 4, PURPLE)],
+            victim: (3, 4),
+            enclosed: true,
+        },
+        EnclosureCase {
+            name: "walls on three sides but the fourth open",
+            rules: RuleSet::CLASSIC,
+            murdered: &[],
+            walls: &[(3, 4, 'E')],
+            pieces: &[(3, 4, WHITE)],
+            victim: (3, 4),
+            enclosed: false,
+        },
+        EnclosureCase {
+            name: "a wall on the neighbouring square's side",
+            rules: RuleSet::CLASSIC,
+            murdered: &[],
+            // the south wall of the square above
+            walls: &[(3, 3, '8')],
+            pieces: &[(3, 4, WHITE), (4, 4, PURPLE), (3, 5, PURPLE), (2, 4, PURPLE)],
+            victim: (3, 4),
+            enclosed: true,
+        },
+        EnclosureCase {
+            name: "in a corner of the board",
+            rules: RuleSet::CLASSIC,
+            murdered: &[],
+            walls: &[],
+            pieces: &[(0, 0, WHITE), (1, 0, PURPLE), (0, 1, PURPLE)],
+            victim: (0, 0),
+            enclosed: true,
+        },
+        EnclosureCase {
+            name: "against the edge of the board with a side open",
+            rules: RuleSet::CLASSIC,
+            murdered: &[],
+            walls: &[],
+            pieces: &[(0, 4, WHITE), (0, 3, PURPLE), (1, 4, PURPLE)],
+            victim: (0, 4),
+            enclosed: false,
+        },
+        EnclosureCase {
+            name: "the magistrate when he can kill",
+            rules: RuleSet::CLASSIC,
+            murdered: &[],
+            walls: &[],
+            pieces: &[
+                (3, 4, WHITE),
+                (3, 3, PURPLE),
+                (4, 4, PURPLE),
+                (3, 5, PURPLE),
+                (2, 4, SquareOccupant::Magistrate(Team::Neutral)),
+            ],
+            victim: (3, 4),
+            enclosed: true,
+        },
+        EnclosureCase {
+            name: "the magistrate controlled by the victim's team",
+            rules: RuleSet::CLASSIC,
+            murdered: &[Team::Purple],
+            walls: &[],
+            pieces: &[
+                (3, 4, WHITE),
+                (3, 3, PURPLE),
+                (4, 4, PURPLE),
+                (3, 5, PURPLE),
+                (2, 4, SquareOccupant::Magistrate(Team::Neutral)),
+            ],
+            victim: (3, 4),
+            enclosed: true,
+        },
+        EnclosureCase {
+            name: "the magistrate when he only blocks",
+            rules: RuleSet::FORUM,
+            murdered: &[],
+            walls: &[],
+            pieces: &[
+                (3, 4, WHITE),
+                (3, 3, PURPLE),
+                (4, 4, PURPLE),
+                (3, 5, PURPLE),
+                (2, 4, SquareOccupant::Magistrate(Team::Neutral)),
+            ],
+            victim: (3, 4),
+            enclosed: false,
+        },
+        EnclosureCase {
+            name: "neutral guards",
+            rules: RuleSet::CLASSIC,
+            murdered: &[],
+            walls: &[],
+            pieces: &[
+                (3, 4, WHITE),
+                (3, 3, PURPLE),
+                (4, 4, PURPLE),
+                (3, 5, PURPLE),
+                (2, 4, SquareOccupant::Guard(Team::Neutral)),
+            ],
+            victim: (3, 4),
+            enclosed: true,
+        },
+        EnclosureCase {
+            name: "guards controlled by the enemy",
+            rules: RuleSet::CLASSIC,
+            murdered: &[Team::Purple],
+            walls: &[],
+            pieces: &[
+                (3, 4, WHITE),
+                (3, 3, PURPLE),
+                (4, 4, PURPLE),
+                (3, 5, SquareOccupant::Guard(Team::Neutral)),
+                (2, 4, SquareOccupant::Guard(Team::Neutral)),
+            ],
+            victim: (3, 4),
+            enclosed: true,
+        },
+        EnclosureCase {
+            name: "guards controlled by the victim's own team",
+            rules: RuleSet::CLASSIC,
+            murdered: &[Team::White],
+            walls: &[],
+            pieces: &[
+                (3, 4, WHITE),
+                (3, 3, PURPLE),
+                (4, 4, PURPLE),
+                (3, 5, PURPLE),
+                (2, 4, SquareOccupant::Guard(Team::Neutral)),
+            ],
+            victim: (3, 4),
+            enclosed: false,
+        },
+        EnclosureCase {
+            name: "own guards against a wall",
+            rules: RuleSet::CLASSIC,
+            murdered: &[Team::White],
+            walls: &[(3, 4, 'E')],
+            pieces: &[(3, 4, WHITE), (2, 4, SquareOccupant::Guard(Team::Neutral))],
+            victim: (3, 4),
+            enclosed: false,
+        },
+    ];
+
+    #[test]
+    fn enclosure() {
+        for case in ENCLOSURE_CASES {
+            let mut game = game(case.rules, case.walls, case.pieces);
+            for victim in case.murdered {
+                game.flip_guards(*victim);
+            }
+            let (x, y) = case.victim;
+            assert_eq!(game.is_enclosed(BoardSquareCoords(x, y)), case.enclosed, "{}", case.name);
+        }
+    }
+
+    #[test]
+    fn only_citizens_can_be_enclosed() {
+        for occupant in [
+            SquareOccupant::Empty,
+            SquareOccupant::Guard(Team::Neutral),
+            SquareOccupant::Magistrate(Team::Neutral),
+        ] {
+            let game = game(RuleSet::CLASSIC, &[], &[(0, 0, occupant), (1, 0, PURPLE), (0, 1, PURPLE)]);
+            assert!(!game.is_enclosed(BoardSquareCoords(0, 0)));
+        }
+    }
+
+    #[test]
+    fn enclosing_move_murders() {
+        // white closes the last gap around a purple citizen in the corner
+        let mut game = game(
+            RuleSet::CLASSIC,
+            &[],
+            &[(0, 0, PURPLE), (1, 0, WHITE), (0, 2, WHITE), (3, 4, PURPLE)],
+        );
+        assert!(game.make_move(Team::White, BoardSquareCoords(0, 2), BoardSquareCoords(0, 1)));
+        assert!(game.murder_happened.get());
+        assert!(game.board.squares[&BoardSquareCoords(0, 0)].occupant == SquareOccupant::Empty);
+        assert_eq!(game.game_over, None);
+    }
+
+    // the controllers as the game tracks them, and as shown on the board
+    fn controllers(game: &GameState) -> (Team, Team) {
+        let shown = |wanted: fn(SquareOccupant) -> Option<Team>| {
+            game.board.squares.values().find_map(|square| wanted(square.occupant))
+        };
+        let guards = shown(|occupant| match occupant {
+            SquareOccupant::Guard(team) => Some(team),
+            _ => None,
+        });
+        let magistrate = shown(|occupant| match occupant {
+            SquareOccupant::Magistrate(team) => Some(team),
+            _ => None,
+        });
+        assert_eq!(guards, Some(game.get_guard_controller()));
+        assert_eq!(magistrate, Some(game.get_magistrate_controller()));
+        (game.get_guard_controller(), game.get_magistrate_controller())
+    }
+
+    // three murders in a row, each closing in on a citizen in a corner: white kills, then purple,
+    // then white again
+    fn play_murders(rules: RuleSet) -> Vec<(Team, Team)> {
+        let mut game = game(
+            rules,
+            &[],
+            &[
+                // first victim, and the white citizens that trap it
+                (0, 0, PURPLE),
+                (1, 0, WHITE),
+                (0, 2, WHITE),
+                // second victim, and the purple citizens that trap it
+                (6, 0, WHITE),
+                (5, 0, PURPLE),
+                (6, 2, PURPLE),
+                // third victim, and the white citizens that trap it
+                (0, 8, PURPLE),
+                (1, 8, WHITE),
+                (0, 6, WHITE),
+                // survivors on both sides, so the game doesn't end
+                (3, 3, PURPLE),
+                (3, 5, WHITE),
+                (5, 5, SquareOccupant::Guard(Team::Neutral)),
+                (1, 4, SquareOccupant::Magistrate(Team::Neutral)),
+            ],
+        );
+        let mut seen = vec![controllers(&game)];
+        for (team, from, to) in [
+            (Team::White, (0, 2), (0, 1)),
+            (Team::Purple, (6, 2), (6, 1)),
+            (Team::White, (0, 6), (0, 7)),
+        ] {
+            game.murder_happened.set(false);
+            let (from, to) = (BoardSquareCoords(from.0, from.1), BoardSquareCoords(to.0, to.1));
+            assert!(game.make_move(team, from, to));
+            assert!(game.murder_happened.get(), "{team} should have murdered");
+            seen.push(controllers(&game));
+        }
+        assert_eq!(game.game_over, None);
+        seen
+    }
+
+    #[test]
+    fn allegiance_with_guards_joining_the_victim() {
+        assert_eq!(
+            play_murders(RuleSet::CLASSIC),
+            [
+                (Team::Neutral, Team::Neutral),
+                // purple was murdered first, so the guards join purple
+                (Team::Purple, Team::White),
+                (Team::White, Team::Purple),
+                (Team::Purple, Team::White),
+            ]
+        );
+    }
+
+    #[test]
+    fn allegiance_with_guards_joining_the_murderer() {
+        assert_eq!(
+            play_murders(RuleSet::FORUM),
+            [
+                (Team::Neutral, Team::Neutral),
+                // white murdered first, so the guards join white
+                (Team::White, Team::Purple),
+                (Team::Purple, Team::White),
+                (Team::White, Team::Purple),
+            ]
+        );
+    }
+
+    // a citizen each, with the guards and magistrate well out of the way
+    const QUIET_BOARD: &[(u32, u32, SquareOccupant)] = &[
+        (3, 3, PURPLE),
+        (3, 5, WHITE),
+        (5, 5, SquareOccupant::Guard(Team::Neutral)),
+        (1, 4, SquareOccupant::Magistrate(Team::Neutral)),
+    ];
+
+    #[test]
+    fn moves_without_a_murder_keep_allegiance() {
+        let mut game = game(RuleSet::CLASSIC, &[], QUIET_BOARD);
+        assert!(game.make_move(Team::White, BoardSquareCoords(3, 5), BoardSquareCoords(3, 6)));
+        assert_eq!(controllers(&game), (Team::Neutral, Team::Neutral));
+    }
+
+    #[test]
+    fn only_the_controller_moves_the_guards_and_magistrate() {
+        let mut game = game(RuleSet::CLASSIC, &[], QUIET_BOARD);
+        let guard = (BoardSquareCoords(5, 5), BoardSquareCoords(5, 6));
+        let magistrate = (BoardSquareCoords(1, 4), BoardSquareCoords(1, 5));
+        // nobody controls them until the first murder
+        for team in [Team::White, Team::Purple] {
+            game.turn = team;
+            assert!(!game.valid_move(guard.0, guard.1));
+            assert!(!game.valid_move(magistrate.0, magistrate.1));
+        }
+        // purple is murdered, so purple gets the guards and white the magistrate
+        game.flip_guards(Team::Purple);
+        game.turn = Team::Purple;
+        assert!(game.valid_move(guard.0, guard.1));
+        assert!(!game.valid_move(magistrate.0, magistrate.1));
+        game.turn = Team::White;
+        assert!(!game.valid_move(guard.0, guard.1));
+        assert!(game.valid_move(magistrate.0, magistrate.1));
+    }
+}
@@ -0,0 +1,1868 @@
+use std::collections::hash_map::RandomState;
+use std::collections::BTreeMap;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::board::BoardSquareCoords;
+use crate::discovery::Announcer;
+use crate::game::{EndReason, RuleSet, Team, TimeControl, WinState};
+use crate::net::{
+    Announcement, Capability, ClientMessage, Encoding, OfferAction, ParseMessageError, ProtocolError, ProtocolMessage,
+    Ready, RoomInfo, DEFAULT_ROOM, PEER_TIMEOUT_SECS, PING_INTERVAL_SECS, PROTOCOL_VERSION, SEAT_GRACE_PERIOD_SECS,
+};
+use crate::room::{Room, RoomDefaults};
+
+use tracing::{debug, error, info, warn};
+use tungstenite::Message;
+
+// shared by the TCP and WebSocket accept loops, so taking an id has to be a single step
+static ID_SEQ: AtomicU32 = AtomicU32::new(0);
+// how often a WebSocket client's thread stops waiting for input to send what's queued for it
+const WEBSOCKET_POLL_MILLIS: u64 = 20;
+// a client can send this many chat messages in quick succession, and after that one per second
+const CHAT_BURST: f64 = 5.0;
+const CHAT_MESSAGES_PER_SEC: f64 = 1.0;
+
+#[allow(clippy::enum_variant_names)]
+enum ServerEvent {
+    ClientConnected(ClientConnection),
+    ClientDisconnected(u32),
+    ClientMessage(u32, Result<ClientMessage, ParseMessageError>),
+    // hang up on everyone and stop the server thread
+    Shutdown,
+}
+
+// how messages get back to a client
+#[derive(Debug)]
+enum Transport {
+    // written straight to the stream, one line at a time
+    Tcp,
+    // handed to the client's own thread, which wraps each line in a WebSocket message; None asks
+    // it to close the connection once everything before it has been sent
+    WebSocket(Sender<Option<String>>),
+}
+
+#[derive(Debug)]
+struct ClientConnection {
+    stream: Arc<TcpStream>,
+    transport: Transport,
+    team: Option<Team>,
+    room: Option<String>,
+    spectating: bool,
+    ready: bool,
+    session: Option<String>,
+    // None until the client has said hello; nothing else is accepted before then
+    capabilities: Option<Vec<Capability>>,
+    encoding: Encoding,
+    // how many chat messages the client can send right now, topped up over time
+    chat_allowance: f64,
+    chat_refilled: Instant,
+    // when anything last arrived from the client, so that silent connections can be dropped
+    last_heard: Instant,
+    last_ping: Instant,
+    // also the token for the next ping
+    pings_sent: u64,
+    // the token of the ping we're waiting to have answered, and when it was sent
+    pending_ping: Option<(u64, Instant)>,
+    id: u32,
+}
+
+impl ClientConnection {
+    fn disconnect(&self) {
+        match &self.transport {
+            Transport::Tcp => {
+                let _ = self.stream.shutdown(Shutdown::Both);
+            }
+            Transport::WebSocket(outgoing) => {
+                let _ = outgoing.send(None);
+            }
+        }
+    }
+    // drops the connection without waiting on the client, for when it has stopped responding
+    fn hang_up(&self) {
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+    fn supports(&self, capability: Capability) -> bool {
+        self.capabilities
+            .as_ref()
+            .is_some_and(|caps| caps.contains(&capability))
+    }
+    // how the client is named in chat
+    fn display_name(&self) -> String {
+        match self.team {
+            Some(team) => team.as_network_string(),
+            None if self.spectating => format!("spectator-{}", self.id),
+            None => format!("guest-{}", self.id),
+        }
+    }
+    // spends one message of the client's chat allowance, or returns false if it has run out
+    fn take_chat_allowance(&mut self) -> bool {
+        let now = Instant::now();
+        let refill = now.duration_since(self.chat_refilled).as_secs_f64() * CHAT_MESSAGES_PER_SEC;
+        self.chat_allowance = (self.chat_allowance + refill).min(CHAT_BURST);
+        self.chat_refilled = now;
+        if self.chat_allowance >= 1.0 {
+            self.chat_allowance -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+impl std::fmt::Display for ClientConnection {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:?}", self.stream)
+    }
+}
+
+// a seat kept open for a player whose connection dropped, so they can reclaim it with `resume`
+struct Reservation {
+    token: String,
+    room: String,
+    team: Team,
+    expires: Instant,
+}
+
+struct Server {
+    clients: Vec<ClientConnection>,
+    rooms: BTreeMap<String, Room>,
+    reservations: Vec<Reservation>,
+    room_defaults: RoomDefaults,
+    // including the main room
+    max_rooms: usize,
+    // None if we're keeping quiet about being here
+    announcer: Option<(Announcer, Announcement)>,
+}
+impl Server {
+    fn event_loop(&mut self, receiver: Receiver<ServerEvent>) {
+        loop {
+            // wake up regularly even when nothing is happening so that held seats can expire, and
+            // in time to catch the next flag falling
+            let wake_up = self
+                .next_flag_fall()
+                .map_or(Duration::from_secs(1), |deadline| {
+                    deadline.saturating_duration_since(Instant::now())
+                })
+                .min(Duration::from_secs(1));
+            let event = receiver.recv_timeout(wake_up);
+            // before handling the event, so that a move arriving after a player's time has run out
+            // doesn't count
+            self.check_clocks();
+            match event {
+                Ok(event) => match event {
+                    ServerEvent::ClientConnected(client) => {
+                        info!("Client {:?} connected", client);
+                        self.clients.push(client);
+                    }
+                    ServerEvent::ClientDisconnected(client_id) => {
+                        info!("Client {:?} disconnected", client_id);
+                        self.hold_seat(client_id);
+                        self.leave_room(client_id, false);
+                        self.clients.retain(|c| c.id != client_id);
+                    }
+                    ServerEvent::ClientMessage(client_id, command) => {
+                        if matches!(command, Ok(ClientMessage::Ping(_) | ClientMessage::Pong(_))) {
+                            debug!("Client {:?} sent message: {:?}", client_id, command);
+                        } else {
+                            info!("Client {:?} sent message: {:?}", client_id, command);
+                        }
+                        self.handle_command(client_id, command);
+                    }
+                    ServerEvent::Shutdown => {
+                        info!("Shutting down, disconnecting {} clients", self.clients.len());
+                        for client in &self.clients {
+                            client.hang_up();
+                        }
+                        return;
+                    }
+                },
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(e) => {
+                    error!("Could not receive event: {e}");
+                }
+            }
+            self.expire_reservations();
+            self.check_heartbeats();
+            self.announce();
+        }
+    }
+    // tells the local network how many rooms and free seats we have, every few seconds
+    fn announce(&mut self) {
+        let now = Instant::now();
+        if !self.announcer.as_ref().is_some_and(|(announcer, _)| announcer.due(now)) {
+            return;
+        }
+        let rooms = self.rooms.len() as u32;
+        let open_seats = self.open_seats();
+        if let Some((announcer, announcement)) = &mut self.announcer {
+            announcement.rooms = rooms;
+            announcement.open_seats = open_seats;
+            announcer.announce(announcement, now);
+        }
+    }
+    // pings clients that are due one, and hangs up on those we haven't heard from in too long
+    fn check_heartbeats(&mut self) {
+        let now = Instant::now();
+        let mut pings = Vec::new();
+        for client in self.clients.iter_mut() {
+            if now.duration_since(client.last_heard) > Duration::from_secs(PEER_TIMEOUT_SECS) {
+                // the client's reader thread sees the stream close and reports the disconnect,
+                // so its seat is held like any other dropped connection
+                warn!("Client {} has gone silent, disconnecting", client.id);
+                client.hang_up();
+                continue;
+            }
+            // there's no way to send anything before the encoding has been agreed in the hello
+            if client.capabilities.is_some()
+                && now.duration_since(client.last_ping) >= Duration::from_secs(PING_INTERVAL_SECS)
+            {
+                let token = client.pings_sent;
+                client.pings_sent += 1;
+                client.last_ping = now;
+                client.pending_ping = Some((token, now));
+                pings.push((client.id, token));
+            }
+        }
+        for (client_id, token) in pings {
+            self.send(Some(client_id), ProtocolMessage::Ping(token));
+        }
+    }
+    fn pong(&mut self, client_id: u32, token: u64) {
+        let client = self.client_mut(client_id);
+        // a late answer to an older ping still shows the client is alive, but says nothing useful
+        // about its latency
+        if let Some((_, sent)) = client.pending_ping.filter(|(expected, _)| *expected == token) {
+            client.pending_ping = None;
+            debug!("Client {client_id} latency is {}ms", sent.elapsed().as_millis());
+        }
+    }
+    fn handle_command(&mut self, client_id: u32, command: Result<ClientMessage, ParseMessageError>) {
+        self.client_mut(client_id).last_heard = Instant::now();
+        if self.client(client_id).capabilities.is_none() {
+            self.hello(client_id, command);
+            return;
+        }
+        let command = match command {
+            Ok(command) => command,
+            Err(e) => {
+                self.send_error(client_id, e.kind, &e.reason);
+                return;
+            }
+        };
+        match command {
+            ClientMessage::Hello(..) => {
+                self.send_error(client_id, ProtocolError::UnknownCommand, "HELLO has already been sent");
+            }
+            ClientMessage::Join(team) => match self.client(client_id).room.clone() {
+                // every seat needs an opponent, which rules out the neutral team
+                Some(_) if !matches!(team, Team::Purple | Team::White) => {
+                    self.send_error(
+                        client_id,
+                        ProtocolError::InvalidTeam,
+                        &format!("There is no {team} seat"),
+                    );
+                }
+                Some(room) if self.seat_taken(&room, team, client_id) => {
+                    self.send_error(
+                        client_id,
+                        ProtocolError::InvalidTeam,
+                        &format!("Team {team} has already been taken"),
+                    );
+                }
+                Some(room) => {
+                    self.set_client_team(client_id, team);
+                    let token = new_session_token(client_id);
+                    self.client_mut(client_id).session = Some(token.clone());
+                    self.send_room(&room, ProtocolMessage::TeamJoin(team));
+                    self.send(Some(client_id), ProtocolMessage::Session(token));
+                    self.send_sync(client_id, &room);
+                }
+                None => {
+                    self.send_error(
+                        client_id,
+                        ProtocolError::InvalidRoom,
+                        "You must enter a room before joining a team",
+                    );
+                }
+            },
+            ClientMessage::Move(team, from, to) => {
+                self.play_move(client_id, team, from, to);
+            }
+            ClientMessage::Resume(token) => self.resume(client_id, &token),
+            ClientMessage::Ready => {
+                let client = self.client_mut(client_id);
+                match (client.team, client.room.clone()) {
+                    (Some(_), Some(room)) => {
+                        client.ready = true;
+                        self.try_start(&room);
+                    }
+                    _ => {
+                        self.send_error(
+                            client_id,
+                            ProtocolError::InvalidTeam,
+                            "You must join a team before you can be ready",
+                        );
+                    }
+                }
+            }
+            ClientMessage::Spectate if !self.client(client_id).supports(Capability::Spectate) => {
+                self.send_error(
+                    client_id,
+                    ProtocolError::UnknownCommand,
+                    "Spectating was not negotiated in HELLO",
+                );
+            }
+            ClientMessage::Spectate => match self.client(client_id).room.clone() {
+                Some(room) => {
+                    let client = self.client_mut(client_id);
+                    let was_playing = client.team.take().is_some();
+                    client.spectating = true;
+                    client.ready = false;
+                    if was_playing {
+                        self.vacate_seat(&room);
+                    }
+                    info!("Client {client_id} is spectating in room {room}");
+                    self.send_sync(client_id, &room);
+                }
+                None => {
+                    self.send_error(
+                        client_id,
+                        ProtocolError::InvalidRoom,
+                        "You must enter a room before spectating",
+                    );
+                }
+            },
+            ClientMessage::Rules(action, rules) => self.rules(client_id, action, rules),
+            ClientMessage::RoomList => {
+                self.send(Some(client_id), ProtocolMessage::RoomList(self.room_list()));
+            }
+            ClientMessage::RoomCreate(name) => {
+                if self.rooms.contains_key(&name) {
+                    self.send_error(
+                        client_id,
+                        ProtocolError::InvalidRoom,
+                        &format!("Room {name} already exists"),
+                    );
+                } else if self.rooms.len() >= self.max_rooms {
+                    self.send_error(
+                        client_id,
+                        ProtocolError::InvalidRoom,
+                        "The server has no space for more rooms, try joining one instead",
+                    );
+                } else {
+                    info!("Client {client_id} created room {name}");
+                    self.rooms.insert(name.clone(), Room::new(&self.room_defaults));
+                    self.enter_room(client_id, &name);
+                }
+            }
+            ClientMessage::RoomJoin(name) => {
+                if self.rooms.contains_key(&name) {
+                    self.enter_room(client_id, &name);
+                } else {
+                    self.send_error(
+                        client_id,
+                        ProtocolError::InvalidRoom,
+                        &format!("No such room {name}"),
+                    );
+                }
+            }
+            ClientMessage::RoomLeave => {
+                self.leave_room(client_id, true);
+            }
+            ClientMessage::Chat(text) => self.chat(client_id, text),
+            ClientMessage::Resign => self.resign(client_id),
+            ClientMessage::Draw(action) => self.draw(client_id, action),
+            ClientMessage::Rematch(action) => self.rematch(client_id, action),
+            ClientMessage::TimeControl(..) if !self.client(client_id).supports(Capability::Clock) => {
+                self.send_error(
+                    client_id,
+                    ProtocolError::UnknownCommand,
+                    "Clocks were not negotiated in HELLO",
+                );
+            }
+            ClientMessage::TimeControl(action, time_control) => {
+                self.time_control(client_id, action, time_control)
+            }
+            ClientMessage::Ping(token) => self.send(Some(client_id), ProtocolMessage::Pong(token)),
+            ClientMessage::Pong(token) => self.pong(client_id, token),
+            ClientMessage::Quit => self.quit(client_id),
+        }
+    }
+    // the first line from every client must be `hello <version> [capabilities...]`
+    fn hello(&mut self, client_id: u32, command: Result<ClientMessage, ParseMessageError>) {
+        let capabilities = match command {
+            Ok(ClientMessage::Hello(PROTOCOL_VERSION, capabilities)) => capabilities,
+            other => {
+                let reason = match other {
+                    Ok(ClientMessage::Hello(version, _)) => format!(
+                        "Client speaks protocol {version} but this server speaks {PROTOCOL_VERSION}"
+                    ),
+                    _ => format!(
+                        "Expected HELLO {PROTOCOL_VERSION} before any other command, please upgrade your client"
+                    ),
+                };
+                warn!("Rejecting client {client_id}: {reason}");
+                self.send_error(client_id, ProtocolError::IncompatibleVersion, &reason);
+                self.client(client_id).disconnect();
+                return;
+            }
+        };
+        info!("Client {client_id} said hello with capabilities {:?}", capabilities);
+        self.client_mut(client_id).capabilities = Some(capabilities.clone());
+        self.send(Some(client_id), ProtocolMessage::Hello(PROTOCOL_VERSION, capabilities.clone()));
+        // our hello goes out as text, everything after it in whatever was agreed
+        self.client_mut(client_id).encoding = Encoding::negotiated(&capabilities);
+        self.enter_room(client_id, DEFAULT_ROOM);
+    }
+    fn play_move(&mut self, client_id: u32, team: Team, from: BoardSquareCoords, to: BoardSquareCoords) {
+        let client = self.client(client_id);
+        if client.spectating {
+            self.send_error(client_id, ProtocolError::InvalidMove, "Spectators cannot move");
+            return;
+        }
+        let Some(client_team) = client.team else {
+            self.send_error(client_id, ProtocolError::InvalidMove, "You have not joined a team");
+            return;
+        };
+        if client_team != team {
+            self.send_error(
+                client_id,
+                ProtocolError::InvalidMove,
+                &format!("You are not on team {}", team),
+            );
+            return;
+        }
+        let room_name = client.room.clone().expect("Client has a team but no room");
+        let room = self.rooms.get_mut(&room_name).expect("Could not find room");
+        if room.result.is_some() {
+            self.send_error(client_id, ProtocolError::InvalidMove, "The game is over");
+            return;
+        }
+        if !room.playing {
+            self.send_error(
+                client_id,
+                ProtocolError::InvalidMove,
+                "The game has not started, waiting for an opponent",
+            );
+            return;
+        }
+        if !room.game.make_move(team, from, to) {
+            self.send_error(client_id, ProtocolError::InvalidMove, "Illegal move");
+            return;
+        }
+        room.moves.push((team, from, to));
+        // playing on instead of answering a draw offer turns it down
+        let draw_offer = room.draw_offer.take();
+        if let Some(clock) = &mut room.clock {
+            clock.punch(team, Instant::now());
+        }
+        let game_over = room.game.game_over;
+        self.send_room(&room_name, ProtocolMessage::Move(team, from, to));
+        if let Some(offered_by) = draw_offer {
+            let msg = ProtocolMessage::Draw(OfferAction::Decline, offered_by.opposite());
+            self.send_room(&room_name, msg);
+        }
+        // the first move settles the rules and time control
+        if self.rooms.get(&room_name).is_some_and(|room| room.moves.len() == 1) {
+            self.lapse_terms_offers(&room_name);
+        }
+        match game_over {
+            Some(win) => {
+                info!("Game in room {room_name} is over");
+                self.end_game(&room_name, win, EndReason::Murder);
+            }
+            None => self.send_clock(&room_name),
+        }
+    }
+    fn end_game(&mut self, room_name: &str, win: WinState, reason: EndReason) {
+        let room = self.rooms.get_mut(room_name).expect("Could not find room");
+        room.finish(win, reason);
+        self.send_room(room_name, ProtocolMessage::GameOver(win, reason));
+        self.send_clock(room_name);
+    }
+    // the earliest time a player in any room will run out of time; only games being played count,
+    // as check_clocks ignores the rest
+    fn next_flag_fall(&self) -> Option<Instant> {
+        self.rooms
+            .values()
+            .filter(|room| room.in_progress())
+            .filter_map(|room| room.clock.as_ref()?.deadline())
+            .min()
+    }
+    fn check_clocks(&mut self) {
+        let now = Instant::now();
+        let flagged: Vec<(String, Team)> = self
+            .rooms
+            .iter()
+            .filter(|(_, room)| room.in_progress())
+            .filter_map(|(name, room)| Some((name.clone(), room.clock.as_ref()?.flagged(now)?)))
+            .collect();
+        for (room_name, team) in flagged {
+            info!("{team} ran out of time in room {room_name}");
+            self.end_game(&room_name, WinState::won_by(team.opposite()), EndReason::Timeout);
+        }
+    }
+    fn clock_message(room: &Room) -> Option<ProtocolMessage> {
+        let clock = room.clock.as_ref()?;
+        let now = Instant::now();
+        let millis = |team| clock.remaining(team, now).as_millis() as u64;
+        Some(ProtocolMessage::Clock(
+            millis(Team::Purple),
+            millis(Team::White),
+            clock.running(),
+        ))
+    }
+    fn send_clock(&self, room_name: &str) {
+        let room = self.rooms.get(room_name).expect("Could not find room");
+        if let Some(msg) = Self::clock_message(room) {
+            self.send_room(room_name, msg);
+        }
+    }
+    // the player's team and room, if they're seated in a game
+    fn seat(&self, client_id: u32) -> Option<(Team, String)> {
+        let client = self.client(client_id);
+        Some((client.team?, client.room.clone()?))
+    }
+    fn resign(&mut self, client_id: u32) {
+        let Some((team, room_name)) = self.seat(client_id) else {
+            self.send_error(client_id, ProtocolError::InvalidTeam, "You are not playing");
+            return;
+        };
+        let room = self.rooms.get_mut(&room_name).expect("Could not find room");
+        if !room.in_progress() {
+            self.send_error(client_id, ProtocolError::InvalidMove, "There is no game in progress");
+            return;
+        }
+        info!("{team} resigned in room {room_name}");
+        self.end_game(&room_name, WinState::won_by(team.opposite()), EndReason::Resignation);
+    }
+    fn draw(&mut self, client_id: u32, action: OfferAction) {
+        let Some((team, room_name)) = self.seat(client_id) else {
+            self.send_error(client_id, ProtocolError::InvalidTeam, "You are not playing");
+            return;
+        };
+        let room = self.rooms.get_mut(&room_name).expect("Could not find room");
+        if !room.in_progress() {
+            self.send_error(client_id, ProtocolError::InvalidOffer, "There is no game in progress");
+            return;
+        }
+        let opponent_offered = room.draw_offer == Some(team.opposite());
+        match action {
+            // offering a draw to someone who has already offered one is as good as accepting
+            OfferAction::Offer | OfferAction::Accept if opponent_offered => {
+                info!("Draw agreed in room {room_name}");
+                self.send_room(&room_name, ProtocolMessage::Draw(OfferAction::Accept, team));
+                self.end_game(&room_name, WinState::Draw, EndReason::Agreement);
+            }
+            OfferAction::Offer => {
+                room.draw_offer = Some(team);
+                self.send_room(&room_name, ProtocolMessage::Draw(OfferAction::Offer, team));
+            }
+            OfferAction::Decline if opponent_offered => {
+                room.draw_offer = None;
+                self.send_room(&room_name, ProtocolMessage::Draw(OfferAction::Decline, team));
+            }
+            OfferAction::Accept | OfferAction::Decline => {
+                self.send_error(
+                    client_id,
+                    ProtocolError::InvalidOffer,
+                    "Your opponent has not offered a draw",
+                );
+            }
+        }
+    }
+    fn rematch(&mut self, client_id: u32, action: OfferAction) {
+        let Some((team, room_name)) = self.seat(client_id) else {
+            self.send_error(client_id, ProtocolError::InvalidTeam, "You are not playing");
+            return;
+        };
+        let opponent_present = self.clients.iter().any(|c| {
+            c.room.as_deref() == Some(room_name.as_str()) && c.team == Some(team.opposite())
+        });
+        let room = self.rooms.get_mut(&room_name).expect("Could not find room");
+        if room.result.is_none() {
+            self.send_error(client_id, ProtocolError::InvalidOffer, "The game is not over yet");
+            return;
+        }
+        let opponent_offered = room.rematch_offer == Some(team.opposite());
+        match action {
+            OfferAction::Offer | OfferAction::Accept if opponent_offered && opponent_present => {
+                self.send_room(&room_name, ProtocolMessage::Rematch(OfferAction::Accept, team));
+                self.start_rematch(&room_name);
+            }
+            OfferAction::Offer => {
+                room.rematch_offer = Some(team);
+                self.send_room(&room_name, ProtocolMessage::Rematch(OfferAction::Offer, team));
+            }
+            OfferAction::Decline if opponent_offered => {
+                room.rematch_offer = None;
+                self.send_room(&room_name, ProtocolMessage::Rematch(OfferAction::Decline, team));
+            }
+            OfferAction::Accept if opponent_offered => {
+                self.send_error(client_id, ProtocolError::InvalidOffer, "Your opponent has left");
+            }
+            OfferAction::Accept | OfferAction::Decline => {
+                self.send_error(
+                    client_id,
+                    ProtocolError::InvalidOffer,
+                    "Your opponent has not asked for a rematch",
+                );
+            }
+        }
+    }
+    // swaps the players' colours and starts a new game with the same rules
+    fn start_rematch(&mut self, room_name: &str) {
+        info!("Starting a rematch in room {room_name}");
+        let room = self.rooms.get_mut(room_name).expect("Could not find room");
+        room.reset();
+        if let Some(clock) = &mut room.clock {
+            clock.start(room.game.turn, Instant::now());
+        }
+        let players: Vec<(u32, Team)> = self
+            .clients
+            .iter_mut()
+            .filter(|c| c.room.as_deref() == Some(room_name))
+            .filter_map(|c| {
+                let team = c.team?.opposite();
+                c.team = Some(team);
+                Some((c.id, team))
+            })
+            .collect();
+        for (client_id, team) in players {
+            self.send(Some(client_id), ProtocolMessage::Seat(team));
+        }
+        self.send_room_sync(room_name);
+    }
+    fn chat(&mut self, client_id: u32, text: String) {
+        let client = self.client_mut(client_id);
+        if !client.supports(Capability::Chat) {
+            self.send_error(client_id, ProtocolError::UnknownCommand, "Chat was not negotiated in HELLO");
+            return;
+        }
+        let Some(room_name) = client.room.clone() else {
+            self.send_error(client_id, ProtocolError::InvalidRoom, "You are not in a room");
+            return;
+        };
+        if !client.take_chat_allowance() {
+            self.send_error(client_id, ProtocolError::RateLimited, "You are sending messages too quickly");
+            return;
+        }
+        let sender = client.display_name();
+        info!("Chat in room {room_name} from {sender} (client {client_id}): {text}");
+        let msg = ProtocolMessage::Chat(room_name.clone(), sender, text);
+        for client in self.clients.iter() {
+            if client.room.as_deref() == Some(room_name.as_str()) && client.supports(Capability::Chat) {
+                self.send(Some(client.id), msg.clone());
+            }
+        }
+    }
+    // the seat of a player who may still change the rules and time control of their game; anyone
+    // else is told why they can't
+    fn negotiating_seat(&self, client_id: u32) -> Option<(Team, String)> {
+        let Some((team, room_name)) = self.seat(client_id) else {
+            self.send_error(client_id, ProtocolError::InvalidTeam, "Only players can change the rules");
+            return None;
+        };
+        if self.rooms.get(&room_name).expect("Could not find room").started() {
+            self.send_error(client_id, ProtocolError::InvalidRules, "The game has already started");
+            return None;
+        }
+        Some((team, room_name))
+    }
+    fn rules(&mut self, client_id: u32, action: OfferAction, rules: RuleSet) {
+        let Some((team, room_name)) = self.negotiating_seat(client_id) else {
+            return;
+        };
+        // with nobody to ask, the rules just change; whoever sits down next sees them before they're ready
+        let opponent_seated = self.seat_taken(&room_name, team.opposite(), client_id);
+        let room = self.rooms.get_mut(&room_name).expect("Could not find room");
+        let opponent_offer = room.rules_offer.filter(|(offered_by, _)| *offered_by != team);
+        let accepting = opponent_offer.is_some_and(|(_, offered)| offered == rules);
+        match action {
+            OfferAction::Offer if !opponent_seated => self.set_rules(&room_name, rules),
+            // proposing what the other player already has is as good as accepting
+            OfferAction::Offer | OfferAction::Accept if accepting => {
+                info!("{team} accepted {rules} rules in room {room_name}");
+                self.send_room(&room_name, ProtocolMessage::RulesOffer(OfferAction::Accept, team, rules));
+                self.set_rules(&room_name, rules);
+            }
+            OfferAction::Offer if *room.game.get_rules() == rules => {
+                let reason = format!("The rules are already {rules}");
+                self.send_error(client_id, ProtocolError::InvalidRules, &reason);
+            }
+            OfferAction::Offer => {
+                room.rules_offer = Some((team, rules));
+                self.send_room(&room_name, ProtocolMessage::RulesOffer(OfferAction::Offer, team, rules));
+            }
+            OfferAction::Decline => match opponent_offer {
+                Some((_, offered)) => {
+                    room.rules_offer = None;
+                    let msg = ProtocolMessage::RulesOffer(OfferAction::Decline, team, offered);
+                    self.send_room(&room_name, msg);
+                }
+                None => self.send_error(
+                    client_id,
+                    ProtocolError::InvalidOffer,
+                    "Your opponent has not proposed any rules",
+                ),
+            },
+            OfferAction::Accept => {
+                self.send_error(
+                    client_id,
+                    ProtocolError::InvalidOffer,
+                    &format!("Your opponent has not proposed {rules} rules"),
+                );
+            }
+        }
+    }
+    fn set_rules(&mut self, room_name: &str, rules: RuleSet) {
+        let room = self.rooms.get_mut(room_name).expect("Could not find room");
+        info!("Rules in room {room_name} changed to {rules}");
+        room.rules_offer = None;
+        room.game.set_rules(rules);
+        self.send_room(room_name, ProtocolMessage::Rules(rules));
+    }
+    fn time_control(&mut self, client_id: u32, action: OfferAction, time_control: Option<TimeControl>) {
+        let Some((team, room_name)) = self.negotiating_seat(client_id) else {
+            return;
+        };
+        let opponent_seated = self.seat_taken(&room_name, team.opposite(), client_id);
+        let room = self.rooms.get_mut(&room_name).expect("Could not find room");
+        let opponent_offer = room.time_control_offer.filter(|(offered_by, _)| *offered_by != team);
+        let accepting = opponent_offer.is_some_and(|(_, offered)| offered == time_control);
+        match action {
+            OfferAction::Offer if !opponent_seated => self.set_time_control(&room_name, time_control),
+            OfferAction::Offer | OfferAction::Accept if accepting => {
+                info!("{team} accepted a new time control in room {room_name}");
+                let msg = ProtocolMessage::TimeControlOffer(OfferAction::Accept, team, time_control);
+                self.send_room(&room_name, msg);
+                self.set_time_control(&room_name, time_control);
+            }
+            OfferAction::Offer if room.time_control == time_control => {
+                self.send_error(client_id, ProtocolError::InvalidRules, "That is already the time control");
+            }
+            OfferAction::Offer => {
+                room.time_control_offer = Some((team, time_control));
+                let msg = ProtocolMessage::TimeControlOffer(OfferAction::Offer, team, time_control);
+                self.send_room(&room_name, msg);
+            }
+            OfferAction::Decline => match opponent_offer {
+                Some((_, offered)) => {
+                    room.time_control_offer = None;
+                    let msg = ProtocolMessage::TimeControlOffer(OfferAction::Decline, team, offered);
+                    self.send_room(&room_name, msg);
+                }
+                None => self.send_error(
+                    client_id,
+                    ProtocolError::InvalidOffer,
+                    "Your opponent has not proposed a time control",
+                ),
+            },
+            OfferAction::Accept => {
+                self.send_error(
+                    client_id,
+                    ProtocolError::InvalidOffer,
+                    "Your opponent has not proposed that time control",
+                );
+            }
+        }
+    }
+    fn set_time_control(&mut self, room_name: &str, time_control: Option<TimeControl>) {
+        let room = self.rooms.get_mut(room_name).expect("Could not find room");
+        room.time_control_offer = None;
+        match time_control {
+            Some(time_control) => info!("Time control in room {room_name} changed to {time_control}"),
+            None => info!("Room {room_name} is now untimed"),
+        }
+        room.set_time_control(time_control);
+        if room.playing {
+            if let Some(clock) = &mut room.clock {
+                clock.start(room.game.turn, Instant::now());
+            }
+        }
+        self.send_room(room_name, ProtocolMessage::TimeControl(time_control));
+        self.send_clock(room_name);
+    }
+    fn seat_taken(&self, room_name: &str, team: Team, client_id: u32) -> bool {
+        self.clients.iter().any(|c| {
+            c.id != client_id && c.room.as_deref() == Some(room_name) && c.team == Some(team)
+        }) || self
+            .reservations
+            .iter()
+            .any(|r| r.room == room_name && r.team == team)
+    }
+    // called when a client's connection drops, before it leaves its room
+    fn hold_seat(&mut self, client_id: u32) {
+        let client = self.client_mut(client_id);
+        if let (Some(team), Some(room), Some(token)) =
+            (client.team, client.room.clone(), client.session.take())
+        {
+            // the game carries on while the seat is held, it just can't be our turn for long
+            client.team = None;
+            info!("Holding {team} seat in room {room} for client {client_id}");
+            self.reservations.push(Reservation {
+                token,
+                room,
+                team,
+                expires: Instant::now() + Duration::from_secs(SEAT_GRACE_PERIOD_SECS),
+            });
+        }
+    }
+    // unlike a dropped connection, there's no seat to hold for a client that has said goodbye
+    fn quit(&mut self, client_id: u32) {
+        info!("Client {client_id} quit");
+        self.leave_room(client_id, false);
+        // its reader thread sees the stream close and reports the disconnect as usual
+        self.client(client_id).disconnect();
+    }
+    fn resume(&mut self, client_id: u32, token: &str) {
+        // the old connection may be half-open, and we just haven't noticed it go yet; its seat is held
+        // as if we had, ready to be handed straight over
+        let stale = self
+            .clients
+            .iter()
+            .find(|c| c.id != client_id && c.session.as_deref() == Some(token))
+            .map(|c| c.id);
+        if let Some(stale) = stale {
+            info!("Client {client_id} took over the session of client {stale}, dropping the old connection");
+            // its reader thread reports the disconnect later, by when there's nothing left to hold
+            self.client(stale).hang_up();
+            self.hold_seat(stale);
+            self.leave_room(stale, false);
+        }
+        let Some(idx) = self.reservations.iter().position(|r| r.token == token) else {
+            self.send_error(
+                client_id,
+                ProtocolError::InvalidSession,
+                "Unknown or expired session",
+            );
+            return;
+        };
+        let reservation = self.reservations.remove(idx);
+        self.leave_room(client_id, false);
+        let Reservation { room, team, .. } = reservation;
+        info!("Client {client_id} resumed {team} seat in room {room}");
+        let client = self.client_mut(client_id);
+        client.room = Some(room.clone());
+        client.team = Some(team);
+        client.ready = true;
+        // a fresh token, so the old one can't be used to take the seat a second time
+        let token = new_session_token(client_id);
+        client.session = Some(token.clone());
+        self.send(Some(client_id), ProtocolMessage::Resumed(room.clone(), team));
+        self.send(Some(client_id), ProtocolMessage::Session(token));
+        self.send_sync(client_id, &room);
+        if self.rooms.get(&room).expect("Could not find room").playing {
+            self.send(Some(client_id), ProtocolMessage::Start);
+        } else {
+            self.try_start(&room);
+        }
+    }
+    fn expire_reservations(&mut self) {
+        let now = Instant::now();
+        let (expired, held) = std::mem::take(&mut self.reservations)
+            .into_iter()
+            .partition(|r| r.expires <= now);
+        self.reservations = held;
+        for reservation in expired {
+            let Reservation { room, team, .. } = reservation;
+            info!("Released held {team} seat in room {room}");
+            self.vacate_seat(&room);
+            self.close_room_if_empty(&room);
+        }
+    }
+    // start the game once both seats are filled by players who are ready
+    fn try_start(&mut self, room_name: &str) {
+        let ready = |team| {
+            self.clients.iter().any(|c| {
+                c.room.as_deref() == Some(room_name) && c.team == Some(team) && c.ready
+            })
+        };
+        if ready(Team::Purple) && ready(Team::White) {
+            let room = self.rooms.get_mut(room_name).expect("Could not find room");
+            if !room.playing {
+                info!("Starting game in room {room_name}");
+                room.playing = true;
+                // new players shouldn't inherit a game that has already been decided
+                let finished = room.result.is_some();
+                if finished {
+                    room.reset();
+                }
+                // picks up where it left off if the game was paused while a player was away
+                if let Some(clock) = &mut room.clock {
+                    clock.start(room.game.turn, Instant::now());
+                }
+                if finished {
+                    self.send_room_sync(room_name);
+                }
+                self.send_room(room_name, ProtocolMessage::Start);
+                self.send_clock(room_name);
+            }
+        }
+    }
+    // everything a client needs to catch up with the game in a room
+    fn send_sync(&self, client_id: u32, room_name: &str) {
+        let room = self.rooms.get(room_name).expect("Could not find room");
+        self.send(
+            Some(client_id),
+            ProtocolMessage::Sync(*room.game.get_rules(), room.moves.clone()),
+        );
+        // a resignation or agreed draw can't be worked out from the moves alone
+        if let Some((win, reason)) = room.result {
+            self.send(Some(client_id), ProtocolMessage::GameOver(win, reason));
+        }
+        if let Some(msg) = Self::clock_message(room) {
+            self.send(Some(client_id), msg);
+        }
+        // so that a player coming back can answer a proposal made while they were away
+        if let Some((team, rules)) = room.rules_offer {
+            self.send(Some(client_id), ProtocolMessage::RulesOffer(OfferAction::Offer, team, rules));
+        }
+        if let Some((team, time_control)) = room.time_control_offer {
+            let msg = ProtocolMessage::TimeControlOffer(OfferAction::Offer, team, time_control);
+            self.send(Some(client_id), msg);
+        }
+    }
+    fn send_room_sync(&self, room_name: &str) {
+        for client in self.clients.iter() {
+            if client.room.as_deref() == Some(room_name) {
+                self.send_sync(client.id, room_name);
+            }
+        }
+    }
+    fn enter_room(&mut self, client_id: u32, room_name: &str) {
+        self.leave_room(client_id, false);
+        let room = self
+            .rooms
+            .entry(room_name.to_string())
+            .or_insert_with(|| Room::new(&self.room_defaults));
+        let rules = *room.game.get_rules();
+        let time_control = room.time_control;
+        let layout = room.game.get_layout().to_string();
+        self.client_mut(client_id).room = Some(room_name.to_string());
+        info!("Client {client_id} entered room {room_name}");
+        self.send(Some(client_id), ProtocolMessage::RoomEntered(room_name.to_string()));
+        self.send(Some(client_id), ProtocolMessage::Board(layout));
+        self.send(Some(client_id), ProtocolMessage::Rules(rules));
+        self.send(Some(client_id), ProtocolMessage::TimeControl(time_control));
+    }
+    // set notify to false when the client is going away and can't be told it has left
+    fn leave_room(&mut self, client_id: u32, notify: bool) {
+        let client = self.client_mut(client_id);
+        let was_playing = client.team.take().is_some();
+        client.spectating = false;
+        client.ready = false;
+        client.session = None;
+        let Some(room_name) = client.room.take() else {
+            return;
+        };
+        info!("Client {client_id} left room {room_name}");
+        if was_playing {
+            self.vacate_seat(&room_name);
+        }
+        if notify {
+            self.send(Some(client_id), ProtocolMessage::RoomLeft(room_name.clone()));
+        }
+        self.close_room_if_empty(&room_name);
+    }
+    // the game can't carry on with an empty seat, so it waits, clocks stopped, for someone to fill it
+    fn vacate_seat(&mut self, room_name: &str) {
+        if let Some(room) = self.rooms.get_mut(room_name) {
+            room.playing = false;
+            // whoever takes the seat next hasn't offered anything
+            let draw_offer = room.draw_offer.take();
+            room.rematch_offer = None;
+            if let Some(clock) = &mut room.clock {
+                clock.stop(Instant::now());
+            }
+            if let Some(offered_by) = draw_offer {
+                let msg = ProtocolMessage::Draw(OfferAction::Decline, offered_by.opposite());
+                self.send_room(room_name, msg);
+            }
+            self.lapse_terms_offers(room_name);
+            self.send_clock(room_name);
+        }
+    }
+    // proposals of new rules or a new time control that nobody can answer any more are turned down
+    fn lapse_terms_offers(&mut self, room_name: &str) {
+        let Some(room) = self.rooms.get_mut(room_name) else {
+            return;
+        };
+        let (rules_offer, time_control_offer) = (room.rules_offer.take(), room.time_control_offer.take());
+        if let Some((offered_by, rules)) = rules_offer {
+            let msg = ProtocolMessage::RulesOffer(OfferAction::Decline, offered_by.opposite(), rules);
+            self.send_room(room_name, msg);
+        }
+        if let Some((offered_by, time_control)) = time_control_offer {
+            let msg =
+                ProtocolMessage::TimeControlOffer(OfferAction::Decline, offered_by.opposite(), time_control);
+            self.send_room(room_name, msg);
+        }
+    }
+    fn close_room_if_empty(&mut self, room_name: &str) {
+        let still_occupied = self
+            .clients
+            .iter()
+            .any(|c| c.room.as_deref() == Some(room_name))
+            || self.reservations.iter().any(|r| r.room == room_name);
+        if !still_occupied && room_name != DEFAULT_ROOM {
+            info!("Closing empty room {room_name}");
+            self.rooms.remove(room_name);
+        }
+    }
+    fn open_seats(&self) -> u32 {
+        self.rooms
+            .keys()
+            .map(|room| {
+                [Team::Purple, Team::White]
+                    .into_iter()
+                    // no client has this id, so every seat holder counts
+                    .filter(|team| !self.seat_taken(room, *team, u32::MAX))
+                    .count() as u32
+            })
+            .sum()
+    }
+    fn room_list(&self) -> Vec<RoomInfo> {
+        self.rooms
+            .keys()
+            .map(|name| {
+                let members = self
+                    .clients
+                    .iter()
+                    .filter(|c| c.room.as_deref() == Some(name.as_str()));
+                let (players, spectators) = members.fold((0, 0), |(players, spectators), c| {
+                    (
+                        players + c.team.is_some() as u32,
+                        spectators + c.spectating as u32,
+                    )
+                });
+                RoomInfo {
+                    name: name.clone(),
+                    players,
+                    spectators,
+                }
+            })
+            .collect()
+    }
+    fn client(&self, client_id: u32) -> &ClientConnection {
+        self.clients
+            .iter()
+            .find(|c| c.id == client_id)
+            .expect("Could not find client")
+    }
+    fn client_mut(&mut self, client_id: u32) -> &mut ClientConnection {
+        self.clients
+            .iter_mut()
+            .find(|c| c.id == client_id)
+            .expect("Could not find client")
+    }
+    fn send_error(&self, client_id: u32, err: ProtocolError, msg: &str) {
+        self.send(Some(client_id), ProtocolMessage::Error(err, msg.to_string()));
+    }
+    // broadcast to every client in the named room
+    fn send_room(&self, room_name: &str, msg: ProtocolMessage) {
+        for client in self.clients.iter() {
+            if client.room.as_deref() == Some(room_name) {
+                self.send(Some(client.id), msg.clone());
+            }
+        }
+    }
+    // set client_idx to None to broadcast to all clients
+    fn send(&self, client_id: Option<u32>, msg: ProtocolMessage) {
+        debug!("Sending {:?} to client {:?}", msg, client_id);
+        match client_id {
+            Some(client_id) => {
+                let client = self.client(client_id);
+                // an older client wouldn't understand it
+                if msg.capability().is_some_and(|capability| !client.supports(capability)) {
+                    return;
+                }
+                let line = client.encoding.encode(&msg);
+                match &client.transport {
+                    Transport::Tcp => {
+                        let mut stream: &TcpStream = &client.stream;
+                        let _ = stream.write_all(format!("{line}\n").as_bytes()).map_err(|e| {
+                            error!("Could not send message to client: {e}");
+                        });
+                        let _ = stream.flush().map_err(|e| {
+                            error!("Could not send message to client: {e}");
+                        });
+                    }
+                    Transport::WebSocket(outgoing) => {
+                        // the client's thread has gone if this fails, and will report the disconnect
+                        let _ = outgoing.send(Some(line));
+                    }
+                }
+            }
+            None => {
+                for client in self.clients.iter() {
+                    self.send(Some(client.id), msg.clone());
+                }
+            }
+        }
+    }
+    fn set_client_team(&mut self, client_id: u32, team: Team) {
+        debug!("Setting client {:?} to team {:?}", client_id, team);
+        let client = self.client_mut(client_id);
+        client.team = Some(team);
+        client.spectating = false;
+        client.ready = false;
+    }
+
+    fn new(
+        room_defaults: RoomDefaults,
+        max_rooms: usize,
+        announcer: Option<(Announcer, Announcement)>,
+    ) -> Self {
+        let mut rooms = BTreeMap::new();
+        rooms.insert(DEFAULT_ROOM.to_string(), Room::new(&room_defaults));
+        Self {
+            clients: Vec::new(),
+            rooms,
+            reservations: Vec::new(),
+            room_defaults,
+            max_rooms,
+            announcer,
+        }
+    }
+}
+
+fn new_session_token(client_id: u32) -> String {
+    // RandomState is seeded randomly, which is plenty to make tokens unguessable for a board game
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u32(client_id);
+    if let Ok(now) = SystemTime::now().duration_since(UNIX_EPOCH) {
+        hasher.write_u128(now.as_nanos());
+    }
+    format!("{:016x}", hasher.finish())
+}
+
+// hands out a client id and tells the server thread about the new connection; None if the server
+// has already shut down
+fn register_client(
+    stream: Arc<TcpStream>,
+    transport: Transport,
+    event_tx: &Sender<ServerEvent>,
+) -> Option<u32> {
+    let client_id = ID_SEQ.fetch_add(1, Ordering::Relaxed);
+
+    event_tx
+        .send(ServerEvent::ClientConnected(ClientConnection {
+            stream,
+            transport,
+            team: None,
+            room: None,
+            spectating: false,
+            ready: false,
+            session: None,
+            capabilities: None,
+            encoding: Encoding::Text,
+            chat_allowance: CHAT_BURST,
+            chat_refilled: Instant::now(),
+            last_heard: Instant::now(),
+            last_ping: Instant::now(),
+            pings_sent: 0,
+            pending_ping: None,
+            id: client_id,
+        }))
+        .ok()?;
+    Some(client_id)
+}
+
+// decodes the lines a client sends, following the encoding the server thread picks in response
+// to its hello so that we know how to read everything after it
+struct LineDecoder {
+    encoding: Encoding,
+    said_hello: bool,
+}
+
+impl LineDecoder {
+    fn new() -> Self {
+        Self {
+            encoding: Encoding::Text,
+            said_hello: false,
+        }
+    }
+
+    fn decode(&mut self, line: &str) -> Result<ClientMessage, ParseMessageError> {
+        let command = self.encoding.decode::<ClientMessage>(line);
+        if !self.said_hello {
+            self.said_hello = true;
+            if let Ok(ClientMessage::Hello(PROTOCOL_VERSION, capabilities)) = &command {
+                self.encoding = Encoding::negotiated(capabilities);
+            }
+        }
+        command
+    }
+}
+
+fn handle_client(stream: TcpStream, event_tx: Sender<ServerEvent>) {
+    let stream = Arc::new(stream);
+    let Some(client_id) = register_client(stream.clone(), Transport::Tcp, &event_tx) else {
+        return;
+    };
+    let reader = BufReader::new(stream.as_ref());
+    let mut decoder = LineDecoder::new();
+    for line in reader.lines() {
+        match line {
+            Ok(line) => {
+                let command = decoder.decode(&line);
+                if event_tx.send(ServerEvent::ClientMessage(client_id, command)).is_err() {
+                    // the server has shut down
+                    break;
+                }
+            }
+            Err(e) => {
+                error!("Could not read from client: {e}");
+                break;
+            }
+        }
+    }
+    // nobody to tell if the server has shut down
+    let _ = event_tx.send(ServerEvent::ClientDisconnected(client_id));
+}
+
+// the same protocol as handle_client, with each line carried in its own WebSocket text message
+fn handle_websocket_client(stream: TcpStream, event_tx: Sender<ServerEvent>) {
+    let shutdown_handle = match stream.try_clone() {
+        Ok(handle) => Arc::new(handle),
+        Err(e) => {
+            error!("Could not clone WebSocket stream: {e}");
+            return;
+        }
+    };
+    let mut websocket = match tungstenite::accept(stream) {
+        Ok(websocket) => websocket,
+        Err(e) => {
+            warn!("WebSocket handshake failed: {e}");
+            return;
+        }
+    };
+    // tungstenite can't read and write from different threads, so this thread does both, waking
+    // up regularly to pass on anything the server has queued for the client
+    if let Err(e) = websocket
+        .get_ref()
+        .set_read_timeout(Some(Duration::from_millis(WEBSOCKET_POLL_MILLIS)))
+    {
+        error!("Could not set WebSocket read timeout: {e}");
+        return;
+    }
+    let (outgoing_tx, outgoing_rx) = channel::<Option<String>>();
+    let Some(client_id) =
+        register_client(shutdown_handle, Transport::WebSocket(outgoing_tx), &event_tx)
+    else {
+        return;
+    };
+    let mut decoder = LineDecoder::new();
+    'connection: loop {
+        while let Ok(line) = outgoing_rx.try_recv() {
+            let result = match line {
+                Some(line) => websocket.send(Message::text(line)),
+                None => websocket.close(None),
+            };
+            if let Err(e) = result {
+                error!("Could not send message to WebSocket client: {e}");
+                break 'connection;
+            }
+        }
+        match websocket.read() {
+            Ok(Message::Text(text)) => {
+                for line in text.lines() {
+                    let command = decoder.decode(line);
+                    if event_tx.send(ServerEvent::ClientMessage(client_id, command)).is_err() {
+                        break 'connection;
+                    }
+                }
+            }
+            Ok(Message::Close(_)) => break,
+            // pings are answered by tungstenite itself, and we have no use for binary messages
+            Ok(_) => {}
+            Err(tungstenite::Error::Io(e))
+                if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+            Err(tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed) => break,
+            Err(e) => {
+                error!("Could not read from WebSocket client: {e}");
+                break;
+            }
+        }
+    }
+    // nobody to tell if the server has shut down
+    let _ = event_tx.send(ServerEvent::ClientDisconnected(client_id));
+}
+
+// what a server needs to know to start listening
+pub struct HostOptions {
+    pub bind: IpAddr,
+    // 0 for either port lets the OS pick a free one
+    pub port: u16,
+    pub websocket_port: u16,
+    pub room_defaults: RoomDefaults,
+    // including the main room
+    pub max_rooms: usize,
+    // what the server is called in other players' lists of LAN games
+    pub name: String,
+    // where to announce the server, usually the broadcast address; None to not announce it at all
+    pub announce_to: Option<IpAddr>,
+}
+
+// a server running on background threads, used by guardsd and by the client when it hosts a game;
+// dropping it shuts the server down and disconnects everyone
+pub struct Host {
+    ready: Ready,
+    // where the listeners can be reached from this machine, to wake them up when stopping
+    wake_addrs: [SocketAddr; 2],
+    stopping: Arc<AtomicBool>,
+    event_tx: Sender<ServerEvent>,
+    // only guardsd waits on this
+    #[allow(dead_code)]
+    accept_thread: Option<JoinHandle<()>>,
+}
+
+fn bind(address: IpAddr, port: u16) -> Result<TcpListener, String> {
+    TcpListener::bind((address, port)).map_err(|e| format!("Could not listen on {address}:{port}: {e}"))
+}
+
+// a listener bound to every address can be reached on loopback
+fn local_addr(listener: &TcpListener) -> Result<SocketAddr, String> {
+    let mut addr = listener
+        .local_addr()
+        .map_err(|e| format!("Could not find which port we're listening on: {e}"))?;
+    match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => addr.set_ip(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+        IpAddr::V6(ip) if ip.is_unspecified() => addr.set_ip(IpAddr::V6(Ipv6Addr::LOCALHOST)),
+        _ => {}
+    }
+    Ok(addr)
+}
+
+// accepts connections until the host is stopped, handing each one to its own thread
+fn accept_loop(
+    listener: TcpListener,
+    stopping: Arc<AtomicBool>,
+    event_tx: Sender<ServerEvent>,
+    handle: fn(TcpStream, Sender<ServerEvent>),
+) {
+    for stream in listener.incoming() {
+        if stopping.load(Ordering::SeqCst) {
+            break;
+        }
+        match stream {
+            Ok(stream) => {
+                let event_tx = event_tx.clone();
+                thread::spawn(move || handle(stream, event_tx));
+            }
+            Err(e) => {
+                warn!("Could not accept client connection: {e}");
+            }
+        }
+    }
+}
+
+impl Host {
+    // binds both listeners before returning, so the server can be connected to straight away
+    pub fn start(options: HostOptions) -> Result<Self, String> {
+        let listener = bind(options.bind, options.port)?;
+        let websocket_listener = bind(options.bind, options.websocket_port)?;
+        let wake_addrs = [local_addr(&listener)?, local_addr(&websocket_listener)?];
+        // either port may have been 0, in which case the OS picked one for us
+        let ready = Ready {
+            port: wake_addrs[0].port(),
+            websocket_port: wake_addrs[1].port(),
+        };
+        info!("Server listening on {}:{}", options.bind, ready.port);
+        info!(
+            "Server listening for WebSocket connections on {}:{}",
+            options.bind, ready.websocket_port
+        );
+        let room_defaults = options.room_defaults;
+        info!(
+            "New rooms use {} rules and {}, up to {} rooms",
+            room_defaults.rules,
+            room_defaults
+                .time_control
+                .map_or("no time control".to_string(), |tc| format!("{tc} time control")),
+            options.max_rooms
+        );
+        // not being findable is no reason not to run at all
+        let announcer = options.announce_to.and_then(|to| match Announcer::new(to) {
+            Ok(announcer) => {
+                info!("Announcing server as {} to {to}", options.name);
+                let announcement = Announcement {
+                    protocol_version: PROTOCOL_VERSION,
+                    port: ready.port,
+                    websocket_port: ready.websocket_port,
+                    rooms: 0,
+                    open_seats: 0,
+                    name: options.name.clone(),
+                };
+                Some((announcer, announcement))
+            }
+            Err(e) => {
+                warn!("Could not announce server to {to}: {e}");
+                None
+            }
+        });
+        let (event_tx, event_rx) = channel();
+        let max_rooms = options.max_rooms;
+        thread::spawn(move || {
+            let mut server = Server::new(room_defaults, max_rooms, announcer);
+            server.event_loop(event_rx)
+        });
+        let stopping = Arc::new(AtomicBool::new(false));
+        let (websocket_stopping, websocket_event_tx) = (stopping.clone(), event_tx.clone());
+        thread::spawn(move || {
+            accept_loop(websocket_listener, websocket_stopping, websocket_event_tx, handle_websocket_client)
+        });
+        let (tcp_stopping, tcp_event_tx) = (stopping.clone(), event_tx.clone());
+        let accept_thread = thread::spawn(move || {
+            accept_loop(listener, tcp_stopping, tcp_event_tx, handle_client)
+        });
+        Ok(Self {
+            ready,
+            wake_addrs,
+            stopping,
+            event_tx,
+            accept_thread: Some(accept_thread),
+        })
+    }
+
+    pub fn ready(&self) -> Ready {
+        self.ready
+    }
+
+    // blocks for as long as the server is accepting connections
+    #[allow(dead_code)]
+    pub fn wait(&mut self) {
+        if let Some(accept_thread) = self.accept_thread.take() {
+            let _ = accept_thread.join();
+        }
+    }
+
+    pub fn stop(&self) {
+        if self.stopping.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        info!("Stopping server on port {}", self.ready.port);
+        let _ = self.event_tx.send(ServerEvent::Shutdown);
+        // the accept loops only look at the flag when a connection comes in, so make one
+        for addr in self.wake_addrs {
+            let _ = TcpStream::connect_timeout(&addr, Duration::from_secs(1));
+        }
+    }
+}
+
+impl Drop for Host {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::board::{SquareOccupant, DEFAULT_LAYOUT};
+    use crate::game::GameState;
+    use std::io::Read;
+    use tungstenite::WebSocket;
+
+    fn start_host() -> Host {
+        Host::start(HostOptions {
+            bind: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: 0,
+            websocket_port: 0,
+            room_defaults: RoomDefaults {
+                rules: RuleSet::CLASSIC,
+                time_control: None,
+                layout: DEFAULT_LAYOUT.to_string(),
+            },
+            max_rooms: 4,
+            name: "test".to_string(),
+            announce_to: None,
+        })
+        .expect("Could not start a server")
+    }
+
+    // how the test client reaches the server: a line per message either way
+    enum Link {
+        Tcp(BufReader<TcpStream>, TcpStream),
+        WebSocket(Box<WebSocket<TcpStream>>),
+    }
+
+    impl Link {
+        fn tcp(host: &Host) -> Self {
+            let stream = TcpStream::connect((Ipv4Addr::LOCALHOST, host.ready().port)).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            Link::Tcp(BufReader::new(stream.try_clone().unwrap()), stream)
+        }
+
+        fn websocket(host: &Host) -> Self {
+            let port = host.ready().websocket_port;
+            let stream = TcpStream::connect((Ipv4Addr::LOCALHOST, port)).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            let (websocket, _) = tungstenite::client(format!("ws://127.0.0.1:{port}/"), stream)
+                .expect("WebSocket handshake failed");
+            Link::WebSocket(Box::new(websocket))
+        }
+
+        fn send(&mut self, line: String) {
+            match self {
+                Link::Tcp(_, writer) => writer.write_all(format!("{line}\n").as_bytes()).unwrap(),
+                Link::WebSocket(websocket) => websocket.send(Message::text(line)).unwrap(),
+            }
+        }
+
+        fn recv(&mut self) -> String {
+            match self {
+                Link::Tcp(reader, _) => {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).expect("Server didn't answer");
+                    assert!(!line.is_empty(), "Server hung up");
+                    line.trim_end().to_string()
+                }
+                Link::WebSocket(websocket) => loop {
+                    match websocket.read().expect("Server didn't answer") {
+                        Message::Text(text) => return text.to_string(),
+                        Message::Close(_) => panic!("Server hung up"),
+                        _ => {}
+                    }
+                },
+            }
+        }
+    }
+
+    // a player speaking the protocol as a bot would, in whichever encoding it asks for
+    struct TestClient {
+        link: Link,
+        encoding: Encoding,
+        pings_sent: u64,
+        // the last session token the server gave us
+        session: Option<String>,
+    }
+
+    impl TestClient {
+        fn connect(host: &Host, encoding: Encoding) -> Self {
+            Self::over(Link::tcp(host), encoding)
+        }
+
+        fn over(link: Link, encoding: Encoding) -> Self {
+            let capabilities = Capability::ALL
+                .into_iter()
+                .filter(|c| *c != Capability::Json || encoding == Encoding::Json)
+                .collect();
+            let client = Self::hello(link, capabilities);
+            assert_eq!(client.encoding, encoding);
+            client
+        }
+
+        // a client that only knows some of the protocol, like an older build would
+        fn hello(link: Link, capabilities: Vec<Capability>) -> Self {
+            let mut client = Self {
+                link,
+                encoding: Encoding::Text,
+                pings_sent: 0,
+                session: None,
+            };
+            client.send(ClientMessage::Hello(PROTOCOL_VERSION, capabilities));
+            // the hellos are always text, whatever comes after them
+            let ProtocolMessage::Hello(_, agreed) = client.recv() else {
+                panic!("Expected the server's hello first");
+            };
+            client.encoding = Encoding::negotiated(&agreed);
+            client
+        }
+
+        fn send(&mut self, msg: ClientMessage) {
+            let line = self.encoding.encode(&msg);
+            self.link.send(line);
+        }
+
+        // skips the server's own pings, which come whenever it feels like it
+        fn recv(&mut self) -> ProtocolMessage {
+            loop {
+                let line = self.link.recv();
+                match self.encoding.decode(&line) {
+                    Ok(ProtocolMessage::Ping(_)) => {}
+                    Ok(msg) => return msg,
+                    Err(e) => panic!("Server sent {line:?}: {e}"),
+                }
+            }
+        }
+
+        // everything the server has sent up to now; a ping is answered after everything sent
+        // before it, so once its pong arrives there's nothing else on the way
+        fn replies(&mut self) -> Vec<ProtocolMessage> {
+            let token = self.pings_sent;
+            self.pings_sent += 1;
+            self.send(ClientMessage::Ping(token));
+            let mut replies = Vec::new();
+            loop {
+                match self.recv() {
+                    ProtocolMessage::Pong(pong) if pong == token => return replies,
+                    // tokens are random, so can't be compared between runs
+                    ProtocolMessage::Session(token) => {
+                        self.session = Some(token);
+                        replies.push(ProtocolMessage::Session(String::new()))
+                    }
+                    msg => replies.push(msg),
+                }
+            }
+        }
+    }
+
+    // the first move white can make on the default board, worked out the same way every time
+    fn opening_move() -> (BoardSquareCoords, BoardSquareCoords) {
+        let game = GameState::new(RuleSet::CLASSIC);
+        let squares = game.get_board().get_squares();
+        let mut moves: Vec<_> = squares
+            .iter()
+            .filter(|(_, square)| square.occupant == SquareOccupant::Citizen(Team::White))
+            .flat_map(|(from, _)| squares.keys().map(move |to| (*from, *to)))
+            .filter(|(from, to)| game.valid_move(*from, *to))
+            .map(|(from, to)| ((from.0, from.1, to.0, to.1), (from, to)))
+            .collect();
+        moves.sort_by_key(|(key, _)| *key);
+        moves[0].1
+    }
+
+    // two players sit down, play a move, chat and agree a draw; returns what each of them was sent
+    // after each step
+    fn play_exchange(purple: &mut TestClient, white: &mut TestClient) -> Vec<Vec<ProtocolMessage>> {
+        let (from, to) = opening_move();
+        let steps: Vec<(bool, ClientMessage)> = vec![
+            (true, ClientMessage::RoomList),
+            (true, ClientMessage::Join(Team::Purple)),
+            (false, ClientMessage::Join(Team::White)),
+            (false, ClientMessage::Join(Team::Purple)),
+            (true, ClientMessage::Ready),
+            (false, ClientMessage::Ready),
+            (true, ClientMessage::Move(Team::Purple, from, to)),
+            (false, ClientMessage::Move(Team::White, from, to)),
+            (true, ClientMessage::Chat("well played".to_string())),
+            (true, ClientMessage::Draw(OfferAction::Offer)),
+            (false, ClientMessage::Draw(OfferAction::Accept)),
+        ];
+        let mut transcript = vec![purple.replies(), white.replies()];
+        for (is_purple, msg) in steps {
+            // the sender's pong means the server has dealt with the message, so anything it
+            // broadcast is already on the way to the other player before their own ping
+            let (sender, other) = if is_purple {
+                (&mut *purple, &mut *white)
+            } else {
+                (&mut *white, &mut *purple)
+            };
+            sender.send(msg);
+            let sent = sender.replies();
+            let broadcast = other.replies();
+            if is_purple {
+                transcript.extend([sent, broadcast]);
+            } else {
+                transcript.extend([broadcast, sent]);
+            }
+        }
+        transcript
+    }
+
+    #[test]
+    fn text_and_json_clients_see_the_same_game() {
+        let transcripts: Vec<_> = [Encoding::Text, Encoding::Json]
+            .into_iter()
+            .map(|encoding| {
+                let host = start_host();
+                let mut purple = TestClient::connect(&host, encoding);
+                let mut white = TestClient::connect(&host, encoding);
+                play_exchange(&mut purple, &mut white)
+            })
+            .collect();
+        assert_eq!(transcripts[0], transcripts[1]);
+        let everything: Vec<_> = transcripts[0].iter().flatten().collect();
+        for expected in [
+            ProtocolMessage::Start,
+            ProtocolMessage::Error(
+                ProtocolError::InvalidTeam,
+                "Team Purple has already been taken".to_string(),
+            ),
+            ProtocolMessage::GameOver(WinState::Draw, EndReason::Agreement),
+        ] {
+            assert!(everything.contains(&&expected), "Never sent {expected}");
+        }
+        assert!(everything
+            .iter()
+            .any(|msg| matches!(msg, ProtocolMessage::Move(Team::White, ..))));
+        assert!(everything
+            .iter()
+            .any(|msg| matches!(msg, ProtocolMessage::Chat(..))));
+    }
+
+    #[test]
+    fn tcp_and_websocket_clients_play_each_other() {
+        let host = start_host();
+        let mut purple = TestClient::connect(&host, Encoding::Text);
+        let mut white = TestClient::over(Link::websocket(&host), Encoding::Text);
+        let over_websocket = play_exchange(&mut purple, &mut white);
+        let host = start_host();
+        let mut purple = TestClient::connect(&host, Encoding::Text);
+        let mut white = TestClient::connect(&host, Encoding::Text);
+        assert_eq!(over_websocket, play_exchange(&mut purple, &mut white));
+    }
+
+    // two text players seated and ready to go
+    fn start_game(host: &Host) -> (TestClient, TestClient) {
+        let mut purple = TestClient::connect(host, Encoding::Text);
+        let mut white = TestClient::connect(host, Encoding::Text);
+        purple.send(ClientMessage::Join(Team::Purple));
+        white.send(ClientMessage::Join(Team::White));
+        purple.send(ClientMessage::Ready);
+        white.send(ClientMessage::Ready);
+        purple.replies();
+        assert!(white.replies().contains(&ProtocolMessage::Start));
+        purple.replies();
+        (purple, white)
+    }
+
+    #[test]
+    fn clocks_and_boards_are_only_sent_when_negotiated() {
+        let host = start_host();
+        let mut current = TestClient::connect(&host, Encoding::Text);
+        let mut older = TestClient::hello(Link::tcp(&host), vec![Capability::Spectate, Capability::Chat]);
+        let needs_negotiating = |msg: &ProtocolMessage| msg.capability().is_some();
+        assert!(current.replies().iter().any(needs_negotiating));
+        assert!(!older.replies().iter().any(needs_negotiating));
+
+        let time_control = TimeControl::PRESETS[1].1;
+        older.send(ClientMessage::TimeControl(OfferAction::Offer, time_control));
+        let refused = ProtocolMessage::Error(
+            ProtocolError::UnknownCommand,
+            "Clocks were not negotiated in HELLO".to_string(),
+        );
+        assert_eq!(older.replies(), vec![refused]);
+        // with nobody to agree with, the new time control applies straight away
+        current.send(ClientMessage::Join(Team::Purple));
+        current.send(ClientMessage::TimeControl(OfferAction::Offer, time_control));
+        assert!(current.replies().contains(&ProtocolMessage::TimeControl(time_control)));
+        assert!(!older.replies().iter().any(needs_negotiating));
+    }
+
+    #[test]
+    fn resuming_takes_the_seat_from_a_stale_connection() {
+        let host = start_host();
+        let (mut purple, mut white) = start_game(&host);
+        let token = purple.session.clone().expect("Purple was never given a session");
+        // purple's first connection is still open as far as the server knows
+        let mut resumed = TestClient::connect(&host, Encoding::Text);
+        resumed.send(ClientMessage::Resume(token));
+        let replies = resumed.replies();
+        assert!(replies
+            .iter()
+            .any(|msg| matches!(msg, ProtocolMessage::Resumed(_, Team::Purple))));
+        assert!(replies.contains(&ProtocolMessage::Start));
+        let Link::Tcp(reader, _) = &mut purple.link else {
+            unreachable!();
+        };
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).expect("The old connection wasn't closed");
+
+        white.replies();
+        let (from, to) = opening_move();
+        white.send(ClientMessage::Move(Team::White, from, to));
+        white.replies();
+        assert!(resumed
+            .replies()
+            .contains(&ProtocolMessage::Move(Team::White, from, to)));
+    }
+
+    #[test]
+    fn draw_offers_lapse_out_loud() {
+        let host = start_host();
+        let (mut purple, mut white) = start_game(&host);
+        let (from, to) = opening_move();
+        purple.send(ClientMessage::Draw(OfferAction::Offer));
+        purple.replies();
+        white.send(ClientMessage::Move(Team::White, from, to));
+        let expected = vec![
+            ProtocolMessage::Move(Team::White, from, to),
+            ProtocolMessage::Draw(OfferAction::Decline, Team::White),
+        ];
+        assert!(white.replies().ends_with(&expected));
+        assert!(purple.replies().ends_with(&expected));
+
+        purple.send(ClientMessage::Draw(OfferAction::Offer));
+        purple.replies();
+        white.send(ClientMessage::Spectate);
+        white.replies();
+        let lapsed = ProtocolMessage::Draw(OfferAction::Decline, Team::White);
+        assert!(purple.replies().contains(&lapsed));
+    }
+
+    #[test]
+    fn rules_are_agreed_between_the_players() {
+        let host = start_host();
+        let mut purple = TestClient::connect(&host, Encoding::Text);
+        let mut spectator = TestClient::connect(&host, Encoding::Text);
+        spectator.send(ClientMessage::Spectate);
+        spectator.send(ClientMessage::Rules(OfferAction::Offer, RuleSet::FORUM));
+        assert!(matches!(
+            spectator.replies().last(),
+            Some(ProtocolMessage::Error(ProtocolError::InvalidTeam, _))
+        ));
+
+        // there's nobody to ask until the other seat is filled
+        purple.send(ClientMessage::Join(Team::Purple));
+        purple.send(ClientMessage::Rules(OfferAction::Offer, RuleSet::FORUM));
+        assert!(purple.replies().contains(&ProtocolMessage::Rules(RuleSet::FORUM)));
+
+        let mut white = TestClient::connect(&host, Encoding::Text);
+        white.send(ClientMessage::Join(Team::White));
+        white.replies();
+        purple.replies();
+        purple.send(ClientMessage::Rules(OfferAction::Offer, RuleSet::SENTRIES));
+        let offer = ProtocolMessage::RulesOffer(OfferAction::Offer, Team::Purple, RuleSet::SENTRIES);
+        assert_eq!(purple.replies(), vec![offer.clone()]);
+        assert_eq!(white.replies(), vec![offer]);
+        // only the other player can accept, and only what was offered
+        purple.send(ClientMessage::Rules(OfferAction::Accept, RuleSet::SENTRIES));
+        white.send(ClientMessage::Rules(OfferAction::Accept, RuleSet::CLASSIC));
+        for client in [&mut purple, &mut white] {
+            assert!(matches!(
+                client.replies().as_slice(),
+                [ProtocolMessage::Error(ProtocolError::InvalidOffer, _)]
+            ));
+        }
+        white.send(ClientMessage::Rules(OfferAction::Accept, RuleSet::SENTRIES));
+        white.replies();
+        let accepted = vec![
+            ProtocolMessage::RulesOffer(OfferAction::Accept, Team::White, RuleSet::SENTRIES),
+            ProtocolMessage::Rules(RuleSet::SENTRIES),
+        ];
+        assert_eq!(purple.replies(), accepted);
+
+        // a time control still on the table when the first move is played is turned down
+        let time_control = TimeControl::PRESETS[1].1;
+        white.send(ClientMessage::TimeControl(OfferAction::Offer, time_control));
+        white.send(ClientMessage::Ready);
+        white.replies();
+        purple.send(ClientMessage::Ready);
+        purple.replies();
+        white.replies();
+        let (from, to) = opening_move();
+        white.send(ClientMessage::Move(Team::White, from, to));
+        white.replies();
+        let lapsed = ProtocolMessage::TimeControlOffer(OfferAction::Decline, Team::Purple, time_control);
+        assert!(purple.replies().contains(&lapsed));
+        purple.send(ClientMessage::Rules(OfferAction::Offer, RuleSet::CLASSIC));
+        assert!(matches!(
+            purple.replies().as_slice(),
+            [ProtocolMessage::Error(ProtocolError::InvalidRules, _)]
+        ));
+    }
+}
@@ -20,9 +20,21 @@ pub enum MenuOption {
     LocalGame,
     HostMultiplayer,
     JoinMultiplayer,
+    BrowseLan,
+    WatchMultiplayer,
     Quit
 }
 
+impl MenuOption {
+    pub fn is_available(&self) -> bool {
+        match self {
+            // a browser tab can't listen for connections, nor for LAN announcements
+            Self::HostMultiplayer | Self::BrowseLan => !cfg!(target_arch = "wasm32"),
+            _ => true,
+        }
+    }
+}
+
 pub fn render_menu(state: &mut MenuState, resources: &ResourceBundle) -> Option<MenuOption> {
     let (screen_width, screen_height) = (screen_width(), screen_height());
     let (mouse_x, mouse_y) = mouse_position();
@@ -33,12 +45,17 @@ pub fn render_menu(state: &mut MenuState, resources: &ResourceBundle) -> Option<
     let logo_y = (screen_height - logo.height()) / 2.0 - 100.0;
     draw_texture(logo, logo_x, logo_y, WHITE);
 
-    let menu_items = vec![
+    let menu_items: Vec<(MenuOption, &str)> = [
         (MenuOption::LocalGame, "New Local Game"),
         (MenuOption::HostMultiplayer, "Host Multiplayer Game"),
         (MenuOption::JoinMultiplayer, "Join Multiplayer Game"),
+        (MenuOption::BrowseLan, "Browse LAN Games"),
+        (MenuOption::WatchMultiplayer, "Watch Game"),
         (MenuOption::Quit, "Quit"),
-    ];
+    ]
+    .into_iter()
+    .filter(|(option, _)| option.is_available())
+    .collect();
 
     if is_key_pressed(KeyCode::Down) {
         state.selected_index = (state.selected_index + 1).clamp(0, menu_items.len() as u32 -1);
@@ -52,8 +69,7 @@ pub fn render_menu(state: &mut MenuState, resources: &ResourceBundle) -> Option<
         return Some(menu_items[state.selected_index as usize].0);
     }
 
-    for idx in 0..menu_items.len() {
-        let (item_option, item_label) = menu_items[idx];
+    for (idx, &(item_option, item_label)) in menu_items.iter().enumerate() {
         let item_x = (screen_width - menu_item_bg.width()) / 2.0;
         let item_y = logo_y + logo.height() + (idx as f32 * menu_item_bg.height());
         let item_text_size = measure_text(item_label, None, MENU_FONT_SIZE as u16, 1.0);
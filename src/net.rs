@@ -1,15 +1,329 @@
-use crate::Team;
-use crate::board::BoardSquareCoords;
+use crate::board::{parse_layout, BoardSquareCoords};
+use crate::game::{EndReason, RuleSet, Team, TimeControl, WinState};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::net::Ipv6Addr;
+use std::str::FromStr;
 pub const PORT:u16 = 34865;
+// browsers can't open raw sockets, so guardsd also speaks the same protocol over WebSockets here
+#[allow(dead_code)]
+pub const WEBSOCKET_PORT: u16 = 34866;
+// bumped whenever the line protocol changes in a way older peers can't cope with
+pub const PROTOCOL_VERSION: u32 = 4;
+// every client starts out in this room, and it is never closed
+#[allow(dead_code)]
+pub const DEFAULT_ROOM: &str = "main";
+pub const MAX_ROOM_NAME_LEN: usize = 32;
+// how long the server holds a seat open for a player whose connection dropped
+pub const SEAT_GRACE_PERIOD_SECS: u64 = 60;
+// longest chat message the server will pass on, in characters
+pub const MAX_CHAT_LEN: usize = 200;
+// how often each end pings the other; a ping must be answered with a pong carrying the same token
+pub const PING_INTERVAL_SECS: u64 = 5;
+// a peer we haven't heard anything from for this long is treated as gone
+pub const PEER_TIMEOUT_SECS: u64 = 15;
+// servers announce themselves to this UDP port on the local network, every few seconds
+#[allow(dead_code)]
+pub const DISCOVERY_PORT: u16 = 34867;
+#[allow(dead_code)]
+pub const ANNOUNCE_INTERVAL_SECS: u64 = 2;
+pub const MAX_SERVER_NAME_LEN: usize = 40;
+
+// room names end up in space-separated protocol lines, so keep them to a safe set of characters
+pub fn is_valid_room_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_ROOM_NAME_LEN
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+// optional protocol features; a feature is only used when both ends list it in their `hello`
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Capability {
+    Spectate,
+    // switch to one JSON object per line once the hellos have been exchanged
+    Json,
+    Chat,
+    // time controls, proposals to change them and the clock itself
+    Clock,
+    // rooms that play on a board other than the default one
+    Board,
+}
+
+#[allow(dead_code)]
+impl Capability {
+    // everything this build knows how to do
+    pub const ALL: [Capability; 5] = [
+        Capability::Spectate,
+        Capability::Json,
+        Capability::Chat,
+        Capability::Clock,
+        Capability::Board,
+    ];
+}
+
+impl std::fmt::Display for Capability {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Spectate => write!(f, "spectate"),
+            Self::Json => write!(f, "json"),
+            Self::Chat => write!(f, "chat"),
+            Self::Clock => write!(f, "clock"),
+            Self::Board => write!(f, "board"),
+        }
+    }
+}
+
+impl FromStr for Capability {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "spectate" => Ok(Self::Spectate),
+            "json" => Ok(Self::Json),
+            "chat" => Ok(Self::Chat),
+            "clock" => Ok(Self::Clock),
+            "board" => Ok(Self::Board),
+            _ => Err(()),
+        }
+    }
+}
+
+// the capabilities in a `hello` that we also support; unknown ones are ignored so that newer peers
+// can advertise features we've never heard of
+pub fn parse_capabilities<'a>(names: impl Iterator<Item = &'a str>) -> Vec<Capability> {
+    names
+        .filter_map(|name| name.parse::<Capability>().ok())
+        .filter(|cap| Capability::ALL.contains(cap))
+        .collect()
+}
+
+// a summary of a room, as sent in reply to `room list`
+#[allow(dead_code)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RoomInfo {
+    #[serde(deserialize_with = "deserialize_room_name")]
+    pub name: String,
+    pub players: u32,
+    pub spectators: u32,
+}
+
+impl std::fmt::Display for RoomInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}:{}:{}", self.name, self.players, self.spectators)
+    }
+}
+
+impl FromStr for RoomInfo {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(':');
+        match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(name), Some(players), Some(spectators), None) if is_valid_room_name(name) => {
+                Ok(Self {
+                    name: name.to_string(),
+                    players: players.parse().map_err(|_| ())?,
+                    spectators: spectators.parse().map_err(|_| ())?,
+                })
+            }
+            _ => Err(()),
+        }
+    }
+}
+
+// the line guardsd prints on stdout once it is accepting connections, with the ports it actually
+// bound, so that whatever started it knows when and where to connect
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Ready {
+    pub port: u16,
+    pub websocket_port: u16,
+}
+
+impl std::fmt::Display for Ready {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "ready {} {}", self.port, self.websocket_port)
+    }
+}
+
+// where to find a server, as typed in by a player: a host name or IP address and an optional port,
+// with IPv6 addresses in brackets if a port follows them
+#[allow(dead_code)]
+#[derive(Clone, Debug, PartialEq)]
+pub struct ServerAddress {
+    pub host: String,
+    pub port: u16,
+}
+
+// a DNS name made of dot-separated labels, which also covers IPv4 addresses
+fn is_valid_host_name(host: &str) -> bool {
+    let host = host.strip_suffix('.').unwrap_or(host);
+    !host.is_empty()
+        && host.len() <= 253
+        && host.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+impl std::fmt::Display for ServerAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
+
+// the error says what's wrong in a way that can be shown to the player
+impl FromStr for ServerAddress {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.is_empty() {
+            return Err("Enter the address of a server".to_string());
+        }
+        let (host, port) = if let Some(rest) = s.strip_prefix('[') {
+            let (host, after) = rest
+                .split_once(']')
+                .ok_or_else(|| "IPv6 addresses need a closing ]".to_string())?;
+            let port = match after {
+                "" => None,
+                after => Some(
+                    after
+                        .strip_prefix(':')
+                        .ok_or_else(|| format!("Expected :port after [{host}]"))?,
+                ),
+            };
+            host.parse::<Ipv6Addr>()
+                .map_err(|_| format!("{host} isn't a valid IPv6 address"))?;
+            (host, port)
+        } else if s.matches(':').count() > 1 {
+            // without brackets there's no telling a port from the last part of the address
+            s.parse::<Ipv6Addr>().map_err(|_| {
+                format!("{s} isn't a valid address, IPv6 addresses with a port go in [brackets]")
+            })?;
+            (s, None)
+        } else {
+            let (host, port) = match s.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (s, None),
+            };
+            if !is_valid_host_name(host) {
+                return Err(format!("{host} isn't a valid host name or IP address"));
+            }
+            (host, port)
+        };
+        let port = match port {
+            Some("") => return Err("Expected a port after the :".to_string()),
+            Some(port) => port
+                .parse::<u16>()
+                .ok()
+                .filter(|port| *port != 0)
+                .ok_or_else(|| format!("{port} isn't a valid port"))?,
+            None => PORT,
+        };
+        Ok(Self {
+            host: host.to_string(),
+            port,
+        })
+    }
+}
+
+// what a server broadcasts about itself so that players on the same network can find it, as a
+// single datagram: `guards <version> <port> <websocket port> <rooms> <open seats> <name>`
+#[allow(dead_code)]
+#[derive(Clone, Debug, PartialEq)]
+pub struct Announcement {
+    pub protocol_version: u32,
+    pub port: u16,
+    pub websocket_port: u16,
+    pub rooms: u32,
+    // empty seats across every room, not counting ones held for players who dropped out
+    pub open_seats: u32,
+    pub name: String,
+}
+
+// names are shown as they are in the client, so keep them short and printable
+#[allow(dead_code)]
+pub fn is_valid_server_name(name: &str) -> bool {
+    !name.trim().is_empty()
+        && name.chars().count() <= MAX_SERVER_NAME_LEN
+        && !name.chars().any(char::is_control)
+}
+
+impl std::fmt::Display for Announcement {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "guards {} {} {} {} {} {}",
+            self.protocol_version, self.port, self.websocket_port, self.rooms, self.open_seats, self.name
+        )
+    }
+}
+
+impl FromStr for Announcement {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // the name is whatever is left, spaces and all
+        let mut parts = s.splitn(7, ' ');
+        if parts.next() != Some("guards") {
+            return Err(());
+        }
+        let mut number = || parts.next().ok_or(())?.parse::<u32>().map_err(|_| ());
+        let protocol_version = number()?;
+        let port = u16::try_from(number()?).map_err(|_| ())?;
+        let websocket_port = u16::try_from(number()?).map_err(|_| ())?;
+        let rooms = number()?;
+        let open_seats = number()?;
+        match parts.next() {
+            Some(name) if is_valid_server_name(name) => Ok(Self {
+                protocol_version,
+                port,
+                websocket_port,
+                rooms,
+                open_seats,
+                name: name.to_string(),
+            }),
+            _ => Err(()),
+        }
+    }
+}
 
-// this code is only used by the server, so the client issues warnings about it
 #[allow(dead_code)]
-#[derive(Clone, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
 pub enum ProtocolError {
+    #[serde(rename = "UNKNOWN")]
     UnknownCommand,
+    #[serde(rename = "MISSINGARG")]
     MissingArg,
+    #[serde(rename = "INVALIDTEAM")]
     InvalidTeam,
+    #[serde(rename = "INVALIDMOVE")]
     InvalidMove,
+    #[serde(rename = "INVALIDRULES")]
+    InvalidRules,
+    #[serde(rename = "INVALIDROOM")]
+    InvalidRoom,
+    #[serde(rename = "INVALIDSESSION")]
+    InvalidSession,
+    #[serde(rename = "VERSION")]
+    IncompatibleVersion,
+    #[serde(rename = "INVALIDCHAT")]
+    InvalidChat,
+    #[serde(rename = "RATELIMITED")]
+    RateLimited,
+    #[serde(rename = "INVALIDOFFER")]
+    InvalidOffer,
 }
 
 impl std::fmt::Display for ProtocolError {
@@ -27,32 +341,1108 @@ impl std::fmt::Display for ProtocolError {
             Self::InvalidMove => {
                 write!(f, "INVALIDMOVE")
             }
+            Self::InvalidRules => {
+                write!(f, "INVALIDRULES")
+            }
+            Self::InvalidRoom => {
+                write!(f, "INVALIDROOM")
+            }
+            Self::InvalidSession => {
+                write!(f, "INVALIDSESSION")
+            }
+            Self::IncompatibleVersion => {
+                write!(f, "VERSION")
+            }
+            Self::InvalidChat => {
+                write!(f, "INVALIDCHAT")
+            }
+            Self::RateLimited => {
+                write!(f, "RATELIMITED")
+            }
+            Self::InvalidOffer => {
+                write!(f, "INVALIDOFFER")
+            }
+        }
+    }
+}
+
+impl FromStr for ProtocolError {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "UNKNOWN" => Ok(Self::UnknownCommand),
+            "MISSINGARG" => Ok(Self::MissingArg),
+            "INVALIDTEAM" => Ok(Self::InvalidTeam),
+            "INVALIDMOVE" => Ok(Self::InvalidMove),
+            "INVALIDRULES" => Ok(Self::InvalidRules),
+            "INVALIDROOM" => Ok(Self::InvalidRoom),
+            "INVALIDSESSION" => Ok(Self::InvalidSession),
+            "VERSION" => Ok(Self::IncompatibleVersion),
+            "INVALIDCHAT" => Ok(Self::InvalidChat),
+            "RATELIMITED" => Ok(Self::RateLimited),
+            "INVALIDOFFER" => Ok(Self::InvalidOffer),
+            _ => Err(()),
+        }
+    }
+}
+
+// what a player does with a draw or rematch: propose one, or answer the other player's proposal
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OfferAction {
+    Offer,
+    Accept,
+    Decline,
+}
+
+impl std::fmt::Display for OfferAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Offer => write!(f, "offer"),
+            Self::Accept => write!(f, "accept"),
+            Self::Decline => write!(f, "decline"),
+        }
+    }
+}
+
+impl FromStr for OfferAction {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "offer" => Ok(Self::Offer),
+            "accept" => Ok(Self::Accept),
+            "decline" => Ok(Self::Decline),
+            _ => Err(()),
+        }
+    }
+}
+
+// why a line couldn't be understood; the server sends these straight back to the client as errors
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParseMessageError {
+    pub kind: ProtocolError,
+    pub reason: String,
+}
+
+impl ParseMessageError {
+    fn new(kind: ProtocolError, reason: impl Into<String>) -> Self {
+        Self {
+            kind,
+            reason: reason.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for ParseMessageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} {}", self.kind, self.reason)
+    }
+}
+
+fn parse_team(s: &str) -> Result<Team, ParseMessageError> {
+    Team::from_str(s)
+        .map_err(|_| ParseMessageError::new(ProtocolError::InvalidTeam, format!("Unrecognised team {s}")))
+}
+
+fn parse_rules(s: &str) -> Result<RuleSet, ParseMessageError> {
+    RuleSet::from_str(s)
+        .map_err(|_| ParseMessageError::new(ProtocolError::InvalidRules, format!("Unrecognised rules {s}")))
+}
+
+// "none" for an untimed game
+fn parse_time_control(s: &str) -> Result<Option<TimeControl>, ParseMessageError> {
+    if s == "none" {
+        return Ok(None);
+    }
+    TimeControl::from_str(s).map(Some).map_err(|_| {
+        ParseMessageError::new(
+            ProtocolError::InvalidRules,
+            format!("Invalid time control {s}, expected <base>+<increment> in seconds or none"),
+        )
+    })
+}
+
+fn write_time_control(f: &mut std::fmt::Formatter, time_control: &Option<TimeControl>) -> std::fmt::Result {
+    match time_control {
+        Some(time_control) => write!(f, "{}", time_control),
+        None => write!(f, "none"),
+    }
+}
+
+fn parse_millis(s: &str) -> Result<u64, ParseMessageError> {
+    s.parse::<u64>()
+        .map_err(|_| ParseMessageError::new(ProtocolError::UnknownCommand, format!("Invalid time {s}")))
+}
+
+// layouts go over the wire with their rows separated by slashes rather than newlines
+fn parse_board(s: &str) -> Result<String, ParseMessageError> {
+    parse_layout(&s.replace('/', "\n"))
+        .map_err(|e| ParseMessageError::new(ProtocolError::InvalidRules, format!("Invalid board: {e}")))
+}
+
+fn deserialize_layout<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    let layout = String::deserialize(deserializer)?;
+    parse_layout(&layout).map_err(serde::de::Error::custom)
+}
+
+fn parse_ping_token(s: &str) -> Result<u64, ParseMessageError> {
+    s.parse::<u64>()
+        .map_err(|_| ParseMessageError::new(ProtocolError::UnknownCommand, format!("Invalid ping token {s}")))
+}
+
+// session tokens have to be written back as a single word of the text protocol
+fn parse_session_token(s: &str) -> Result<String, ParseMessageError> {
+    if !s.is_empty() && !s.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        Ok(s.to_string())
+    } else {
+        Err(ParseMessageError::new(ProtocolError::InvalidSession, format!("Invalid session token {s:?}")))
+    }
+}
+
+fn deserialize_session_token<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    let token = String::deserialize(deserializer)?;
+    parse_session_token(&token).map_err(|e| serde::de::Error::custom(e.reason))
+}
+
+fn parse_coords(s: &str) -> Result<BoardSquareCoords, ParseMessageError> {
+    BoardSquareCoords::from_str(s)
+        .map_err(|_| ParseMessageError::new(ProtocolError::InvalidMove, format!("Invalid square {s}")))
+}
+
+fn parse_room_name(s: &str) -> Result<String, ParseMessageError> {
+    if is_valid_room_name(s) {
+        Ok(s.to_string())
+    } else {
+        Err(ParseMessageError::new(
+            ProtocolError::InvalidRoom,
+            "Room names may only contain letters, numbers, '-' and '_'",
+        ))
+    }
+}
+
+// chat senders are named by the server, and have to fit in a single word just like room names
+fn parse_sender(s: &str) -> Result<String, ParseMessageError> {
+    if is_valid_room_name(s) {
+        Ok(s.to_string())
+    } else {
+        Err(ParseMessageError::new(ProtocolError::InvalidChat, format!("Invalid sender {s}")))
+    }
+}
+
+fn parse_chat_text(s: &str) -> Result<String, ParseMessageError> {
+    let text = s.trim();
+    if text.is_empty() {
+        Err(missing_arg("chat <message>"))
+    } else if text.chars().count() > MAX_CHAT_LEN {
+        Err(ParseMessageError::new(
+            ProtocolError::InvalidChat,
+            format!("Chat messages can be at most {MAX_CHAT_LEN} characters"),
+        ))
+    } else if text.chars().any(char::is_control) {
+        Err(ParseMessageError::new(ProtocolError::InvalidChat, "Chat messages must be plain text"))
+    } else {
+        Ok(text.to_string())
+    }
+}
+
+// whatever follows the first `words` words of a line, for free text arguments that may contain
+// spaces of their own
+fn rest_of_line(line: &str, words: usize) -> &str {
+    let mut rest = line.trim_start();
+    for _ in 0..words {
+        rest = rest
+            .split_once(char::is_whitespace)
+            .map(|(_, rest)| rest.trim_start())
+            .unwrap_or("");
+    }
+    rest.trim_end()
+}
+
+fn deserialize_chat_text<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    let text = String::deserialize(deserializer)?;
+    parse_chat_text(&text).map_err(|e| serde::de::Error::custom(e.reason))
+}
+
+fn deserialize_sender<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    let name = String::deserialize(deserializer)?;
+    parse_sender(&name).map_err(|e| serde::de::Error::custom(e.reason))
+}
+
+// the JSON encoding has to hold room names to the same rules as the text one
+fn deserialize_room_name<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    let name = String::deserialize(deserializer)?;
+    parse_room_name(&name).map_err(|e| serde::de::Error::custom(e.reason))
+}
+
+fn parse_offer_action(s: &str) -> Result<OfferAction, ParseMessageError> {
+    OfferAction::from_str(s).map_err(|_| {
+        ParseMessageError::new(ProtocolError::InvalidOffer, format!("Expected offer, accept or decline, not {s}"))
+    })
+}
+
+fn parse_win_state(s: &str) -> Result<WinState, ParseMessageError> {
+    WinState::from_str(s)
+        .map_err(|_| ParseMessageError::new(ProtocolError::UnknownCommand, format!("Unrecognised result {s}")))
+}
+
+fn parse_end_reason(s: &str) -> Result<EndReason, ParseMessageError> {
+    EndReason::from_str(s)
+        .map_err(|_| ParseMessageError::new(ProtocolError::UnknownCommand, format!("Unrecognised reason {s}")))
+}
+
+fn parse_version(s: &str) -> Result<u32, ParseMessageError> {
+    s.parse::<u32>().map_err(|_| {
+        ParseMessageError::new(ProtocolError::IncompatibleVersion, format!("Invalid protocol version {s}"))
+    })
+}
+
+fn parse_move(team: &str, from: &str, to: &str) -> Result<(Team, BoardSquareCoords, BoardSquareCoords), ParseMessageError> {
+    Ok((parse_team(team)?, parse_coords(from)?, parse_coords(to)?))
+}
+
+fn missing_arg(usage: &str) -> ParseMessageError {
+    ParseMessageError::new(ProtocolError::MissingArg, format!("Usage: {usage}"))
+}
+
+fn write_team(f: &mut std::fmt::Formatter, team: &Team) -> std::fmt::Result {
+    write!(f, "{}", team.as_network_string())
+}
+
+// commands sent from a client to the server
+#[allow(dead_code)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "args", rename_all = "snake_case")]
+pub enum ClientMessage {
+    Hello(u32, Vec<Capability>),
+    Join(Team),
+    Ready,
+    Spectate,
+    Move(Team, BoardSquareCoords, BoardSquareCoords),
+    // proposes the rules to the other player, or answers their proposal; only seated players can
+    // change them, and only until the first move
+    Rules(OfferAction, RuleSet),
+    Resume(#[serde(deserialize_with = "deserialize_session_token")] String),
+    RoomList,
+    RoomCreate(#[serde(deserialize_with = "deserialize_room_name")] String),
+    RoomJoin(#[serde(deserialize_with = "deserialize_room_name")] String),
+    RoomLeave,
+    // a message for everyone in our room
+    Chat(#[serde(deserialize_with = "deserialize_chat_text")] String),
+    Resign,
+    Draw(OfferAction),
+    // a new game in the same room with the colours swapped, once the current one is over
+    Rematch(OfferAction),
+    // like the rules, the time control is agreed between the players before the first move
+    TimeControl(OfferAction, Option<TimeControl>),
+    Ping(u64),
+    Pong(u64),
+    // the client is leaving for good, so its seat needn't be held
+    Quit,
+}
+
+impl std::fmt::Display for ClientMessage {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Hello(version, capabilities) => {
+                write!(f, "hello {}", version)?;
+                for capability in capabilities {
+                    write!(f, " {}", capability)?;
+                }
+                Ok(())
+            }
+            Self::Join(team) => {
+                write!(f, "join ")?;
+                write_team(f, team)
+            }
+            Self::Ready => write!(f, "ready"),
+            Self::Spectate => write!(f, "spectate"),
+            Self::Move(team, from, to) => {
+                write!(f, "move ")?;
+                write_team(f, team)?;
+                write!(f, " {} {}", from, to)
+            }
+            Self::Rules(action, rules) => write!(f, "rules {} {}", action, rules),
+            Self::Resume(token) => write!(f, "resume {}", token),
+            Self::RoomList => write!(f, "room list"),
+            Self::RoomCreate(name) => write!(f, "room create {}", name),
+            Self::RoomJoin(name) => write!(f, "room join {}", name),
+            Self::RoomLeave => write!(f, "room leave"),
+            Self::Chat(text) => write!(f, "chat {}", text),
+            Self::Resign => write!(f, "resign"),
+            Self::Draw(action) => write!(f, "draw {}", action),
+            Self::Rematch(action) => write!(f, "rematch {}", action),
+            Self::TimeControl(action, time_control) => {
+                write!(f, "timecontrol {} ", action)?;
+                write_time_control(f, time_control)
+            }
+            Self::Ping(token) => write!(f, "ping {}", token),
+            Self::Pong(token) => write!(f, "pong {}", token),
+            Self::Quit => write!(f, "quit"),
         }
     }
 }
 
+impl FromStr for ClientMessage {
+    type Err = ParseMessageError;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let Some((command, args)) = words.split_first() else {
+            return Err(ParseMessageError::new(ProtocolError::UnknownCommand, "Empty command"));
+        };
+        match (*command, args) {
+            ("hello", [version, capabilities @ ..]) => Ok(Self::Hello(
+                parse_version(version)?,
+                parse_capabilities(capabilities.iter().copied()),
+            )),
+            ("hello", _) => Err(missing_arg("hello <version> [capabilities...]")),
+            ("join", [team, ..]) => Ok(Self::Join(parse_team(team)?)),
+            ("join", _) => Err(missing_arg("join <team>")),
+            ("ready", _) => Ok(Self::Ready),
+            ("spectate", _) => Ok(Self::Spectate),
+            ("move", [team, from, to, ..]) => {
+                let (team, from, to) = parse_move(team, from, to)?;
+                Ok(Self::Move(team, from, to))
+            }
+            ("move", _) => Err(missing_arg("move <team> <from> <to>")),
+            ("rules", [action, preset, ..]) => Ok(Self::Rules(parse_offer_action(action)?, parse_rules(preset)?)),
+            ("rules", _) => Err(missing_arg("rules offer|accept|decline <preset>")),
+            ("resume", [token, ..]) => Ok(Self::Resume(parse_session_token(token)?)),
+            ("resume", _) => Err(missing_arg("resume <token>")),
+            ("room", ["list", ..]) => Ok(Self::RoomList),
+            ("room", ["create", name, ..]) => Ok(Self::RoomCreate(parse_room_name(name)?)),
+            ("room", ["join", name, ..]) => Ok(Self::RoomJoin(parse_room_name(name)?)),
+            ("room", ["leave", ..]) => Ok(Self::RoomLeave),
+            ("room", ["create" | "join"]) => Err(missing_arg("room create|join <name>")),
+            ("room", _) => Err(ParseMessageError::new(
+                ProtocolError::UnknownCommand,
+                "ROOM requires one of list, create, join or leave",
+            )),
+            ("chat", _) => Ok(Self::Chat(parse_chat_text(rest_of_line(line, 1))?)),
+            ("resign", _) => Ok(Self::Resign),
+            ("draw", [action, ..]) => Ok(Self::Draw(parse_offer_action(action)?)),
+            ("draw", _) => Err(missing_arg("draw offer|accept|decline")),
+            ("rematch", [action, ..]) => Ok(Self::Rematch(parse_offer_action(action)?)),
+            ("rematch", _) => Err(missing_arg("rematch offer|accept|decline")),
+            ("timecontrol", [action, time_control, ..]) => Ok(Self::TimeControl(
+                parse_offer_action(action)?,
+                parse_time_control(time_control)?,
+            )),
+            ("timecontrol", _) => Err(missing_arg("timecontrol offer|accept|decline <base>+<increment>|none")),
+            ("ping", [token, ..]) => Ok(Self::Ping(parse_ping_token(token)?)),
+            ("ping", _) => Err(missing_arg("ping <token>")),
+            ("pong", [token, ..]) => Ok(Self::Pong(parse_ping_token(token)?)),
+            ("pong", _) => Err(missing_arg("pong <token>")),
+            ("quit", _) => Ok(Self::Quit),
+            (command, _) => Err(ParseMessageError::new(
+                ProtocolError::UnknownCommand,
+                format!("unknown command {command}"),
+            )),
+        }
+    }
+}
 
-// this code is only used by the server, so the client issues warnings about it
+// messages sent from the server to its clients
 #[allow(dead_code)]
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "args", rename_all = "snake_case")]
 pub enum ProtocolMessage {
+    // the protocol version and the capabilities both ends have in common
+    Hello(u32, Vec<Capability>),
     Error(ProtocolError, String),
     TeamJoin(Team),
     Move(Team, BoardSquareCoords, BoardSquareCoords),
+    Rules(RuleSet),
+    RoomList(Vec<RoomInfo>),
+    RoomEntered(#[serde(deserialize_with = "deserialize_room_name")] String),
+    RoomLeft(#[serde(deserialize_with = "deserialize_room_name")] String),
+    // the room's rules and every move played so far, enough to rebuild the game from scratch
+    Sync(RuleSet, Vec<(Team, BoardSquareCoords, BoardSquareCoords)>),
+    // both seats are filled and both players are ready, so moves will now be accepted
+    Start,
+    // a token that can be used to reclaim our seat with `resume` if the connection drops
+    Session(#[serde(deserialize_with = "deserialize_session_token")] String),
+    Resumed(#[serde(deserialize_with = "deserialize_room_name")] String, Team),
+    // room, sender and message
+    Chat(
+        #[serde(deserialize_with = "deserialize_room_name")] String,
+        #[serde(deserialize_with = "deserialize_sender")] String,
+        #[serde(deserialize_with = "deserialize_chat_text")] String,
+    ),
+    GameOver(WinState, EndReason),
+    // a player offered, accepted or declined a draw; an offer that lapses, because of a move or an
+    // empty seat, is declined on behalf of the player it was made to
+    Draw(OfferAction, Team),
+    // likewise for a rematch; once one is accepted every player is told their new seat
+    Rematch(OfferAction, Team),
+    // the team we're now playing as, after a rematch swapped the colours
+    Seat(Team),
+    TimeControl(Option<TimeControl>),
+    // a player proposed, accepted or declined new rules or a new time control; like a draw offer,
+    // a proposal that lapses is declined on behalf of the player it was made to
+    RulesOffer(OfferAction, Team, RuleSet),
+    TimeControlOffer(OfferAction, Team, Option<TimeControl>),
+    // purple's and white's remaining time in milliseconds, and whose time is running, if anyone's
+    Clock(u64, u64, Option<Team>),
+    Ping(u64),
+    Pong(u64),
+    // the walls of the board the room plays on, in the same format as board.txt
+    Board(#[serde(deserialize_with = "deserialize_layout")] String),
+}
+
+#[allow(dead_code)]
+impl ProtocolMessage {
+    // the capability a client must have listed in its hello to be sent this message, if any
+    pub fn capability(&self) -> Option<Capability> {
+        match self {
+            Self::TimeControl(..) | Self::TimeControlOffer(..) | Self::Clock(..) => Some(Capability::Clock),
+            Self::Board(..) => Some(Capability::Board),
+            _ => None,
+        }
+    }
 }
 
 impl std::fmt::Display for ProtocolMessage {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
+            Self::Hello(version, capabilities) => {
+                write!(f, "hello {}", version)?;
+                for capability in capabilities {
+                    write!(f, " {}", capability)?;
+                }
+                Ok(())
+            }
             Self::Error(e, msg) => {
-                write!(f, "ERROR {} {}", e, msg)
+                write!(f, "error {} {}", e, msg)
             }
             Self::TeamJoin(team) => {
-                write!(f, "TEAMJOIN {}", team)
+                write!(f, "join ")?;
+                write_team(f, team)
             }
             Self::Move(team, from, to) => {
-                write!(f, "MOVE {} {} {}", team, from, to)
+                write!(f, "move ")?;
+                write_team(f, team)?;
+                write!(f, " {} {}", from, to)
+            }
+            Self::Rules(rules) => {
+                write!(f, "rules {}", rules)
+            }
+            Self::RoomList(rooms) => {
+                write!(f, "rooms")?;
+                for room in rooms {
+                    write!(f, " {}", room)?;
+                }
+                Ok(())
+            }
+            Self::RoomEntered(name) => {
+                write!(f, "room {}", name)
+            }
+            Self::RoomLeft(name) => {
+                write!(f, "left {}", name)
+            }
+            Self::Sync(rules, moves) => {
+                write!(f, "sync {}", rules)?;
+                for (team, from, to) in moves {
+                    write!(f, " ")?;
+                    write_team(f, team)?;
+                    write!(f, " {} {}", from, to)?;
+                }
+                Ok(())
+            }
+            Self::Start => {
+                write!(f, "start")
+            }
+            Self::Session(token) => {
+                write!(f, "session {}", token)
+            }
+            Self::Resumed(room, team) => {
+                write!(f, "resumed {} ", room)?;
+                write_team(f, team)
+            }
+            Self::Chat(room, sender, text) => {
+                write!(f, "chat {} {} {}", room, sender, text)
+            }
+            Self::GameOver(win, reason) => {
+                write!(f, "gameover {} {}", win, reason)
+            }
+            Self::Draw(action, team) => {
+                write!(f, "draw {} ", action)?;
+                write_team(f, team)
+            }
+            Self::Rematch(action, team) => {
+                write!(f, "rematch {} ", action)?;
+                write_team(f, team)
+            }
+            Self::Seat(team) => {
+                write!(f, "seat ")?;
+                write_team(f, team)
+            }
+            Self::TimeControl(time_control) => {
+                write!(f, "timecontrol ")?;
+                write_time_control(f, time_control)
+            }
+            Self::RulesOffer(action, team, rules) => {
+                write!(f, "rules {} ", action)?;
+                write_team(f, team)?;
+                write!(f, " {}", rules)
+            }
+            Self::TimeControlOffer(action, team, time_control) => {
+                write!(f, "timecontrol {} ", action)?;
+                write_team(f, team)?;
+                write!(f, " ")?;
+                write_time_control(f, time_control)
+            }
+            Self::Clock(purple, white, running) => {
+                write!(f, "clock {} {}", purple, white)?;
+                if let Some(team) = running {
+                    write!(f, " ")?;
+                    write_team(f, team)?;
+                }
+                Ok(())
+            }
+            Self::Ping(token) => write!(f, "ping {}", token),
+            Self::Pong(token) => write!(f, "pong {}", token),
+            Self::Board(layout) => write!(f, "board {}", layout.replace('\n', "/")),
+        }
+    }
+}
+
+impl FromStr for ProtocolMessage {
+    type Err = ParseMessageError;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let Some((command, args)) = words.split_first() else {
+            return Err(ParseMessageError::new(ProtocolError::UnknownCommand, "Empty message"));
+        };
+        match (*command, args) {
+            ("hello", [version, capabilities @ ..]) => Ok(Self::Hello(
+                parse_version(version)?,
+                parse_capabilities(capabilities.iter().copied()),
+            )),
+            ("error", [kind, ..]) => {
+                let kind = ProtocolError::from_str(kind).map_err(|_| {
+                    ParseMessageError::new(ProtocolError::UnknownCommand, format!("Unknown error {kind}"))
+                })?;
+                Ok(Self::Error(kind, rest_of_line(line, 2).to_string()))
+            }
+            ("join", [team, ..]) => Ok(Self::TeamJoin(parse_team(team)?)),
+            ("move", [team, from, to, ..]) => {
+                let (team, from, to) = parse_move(team, from, to)?;
+                Ok(Self::Move(team, from, to))
+            }
+            // proposals have the action and team before the preset
+            ("rules", [action, team, preset, ..]) => Ok(Self::RulesOffer(
+                parse_offer_action(action)?,
+                parse_team(team)?,
+                parse_rules(preset)?,
+            )),
+            ("rules", [preset, ..]) => Ok(Self::Rules(parse_rules(preset)?)),
+            ("rooms", rooms) => rooms
+                .iter()
+                .map(|room| {
+                    RoomInfo::from_str(room).map_err(|_| {
+                        ParseMessageError::new(ProtocolError::InvalidRoom, format!("Invalid room {room}"))
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()
+                .map(Self::RoomList),
+            ("room", [name, ..]) => Ok(Self::RoomEntered(parse_room_name(name)?)),
+            ("left", [name, ..]) => Ok(Self::RoomLeft(parse_room_name(name)?)),
+            ("sync", [preset, moves @ ..]) => {
+                let rules = parse_rules(preset)?;
+                if moves.len() % 3 != 0 {
+                    return Err(ParseMessageError::new(
+                        ProtocolError::InvalidMove,
+                        "SYNC moves must be <team> <from> <to> triples",
+                    ));
+                }
+                moves
+                    .chunks(3)
+                    .map(|m| parse_move(m[0], m[1], m[2]))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map(|moves| Self::Sync(rules, moves))
+            }
+            ("start", _) => Ok(Self::Start),
+            ("session", [token, ..]) => Ok(Self::Session(parse_session_token(token)?)),
+            ("resumed", [room, team, ..]) => Ok(Self::Resumed(parse_room_name(room)?, parse_team(team)?)),
+            ("chat", [room, sender, _, ..]) => Ok(Self::Chat(
+                parse_room_name(room)?,
+                parse_sender(sender)?,
+                parse_chat_text(rest_of_line(line, 3))?,
+            )),
+            ("gameover", [win, reason, ..]) => Ok(Self::GameOver(parse_win_state(win)?, parse_end_reason(reason)?)),
+            ("draw", [action, team, ..]) => Ok(Self::Draw(parse_offer_action(action)?, parse_team(team)?)),
+            ("rematch", [action, team, ..]) => Ok(Self::Rematch(parse_offer_action(action)?, parse_team(team)?)),
+            ("seat", [team, ..]) => Ok(Self::Seat(parse_team(team)?)),
+            ("timecontrol", [action, team, time_control, ..]) => Ok(Self::TimeControlOffer(
+                parse_offer_action(action)?,
+                parse_team(team)?,
+                parse_time_control(time_control)?,
+            )),
+            ("timecontrol", [time_control, ..]) => Ok(Self::TimeControl(parse_time_control(time_control)?)),
+            ("clock", [purple, white, running @ ..]) => Ok(Self::Clock(
+                parse_millis(purple)?,
+                parse_millis(white)?,
+                running.first().map(|team| parse_team(team)).transpose()?,
+            )),
+            ("ping", [token, ..]) => Ok(Self::Ping(parse_ping_token(token)?)),
+            ("pong", [token, ..]) => Ok(Self::Pong(parse_ping_token(token)?)),
+            ("board", [layout, ..]) => Ok(Self::Board(parse_board(layout)?)),
+            ("hello" | "error" | "join" | "move" | "rules" | "room" | "left" | "sync" | "session" | "resumed" | "chat"
+            | "gameover" | "draw" | "rematch" | "seat" | "timecontrol" | "clock" | "ping" | "pong"
+            | "board", _) => {
+                Err(missing_arg(&format!("{command} is missing arguments")))
+            }
+            (command, _) => Err(ParseMessageError::new(
+                ProtocolError::UnknownCommand,
+                format!("unknown message {command}"),
+            )),
+        }
+    }
+}
+
+// how messages are written on the wire; the hellos are always plain text, and both ends switch
+// encoding straight after them
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Encoding {
+    #[default]
+    Text,
+    // one JSON object per line, e.g. {"type":"move","args":["purple",[1,2],[1,3]]}
+    Json,
+}
+
+#[allow(dead_code)]
+impl Encoding {
+    // the encoding to use after a hello with these (agreed) capabilities
+    pub fn negotiated(capabilities: &[Capability]) -> Self {
+        if capabilities.contains(&Capability::Json) {
+            Self::Json
+        } else {
+            Self::Text
+        }
+    }
+
+    // a single line, without the trailing newline
+    pub fn encode<T: std::fmt::Display + Serialize>(self, msg: &T) -> String {
+        match self {
+            Self::Text => msg.to_string(),
+            Self::Json => serde_json::to_string(msg).expect("Protocol messages can always be serialised"),
+        }
+    }
+
+    pub fn decode<T>(self, line: &str) -> Result<T, ParseMessageError>
+    where
+        T: FromStr<Err = ParseMessageError> + DeserializeOwned,
+    {
+        match self {
+            Self::Text => line.parse(),
+            Self::Json => serde_json::from_str(line)
+                .map_err(|e| ParseMessageError::new(ProtocolError::UnknownCommand, format!("Malformed JSON: {e}"))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::board::DEFAULT_LAYOUT;
+
+    fn square(x: u32, y: u32) -> BoardSquareCoords {
+        BoardSquareCoords(x, y)
+    }
+
+    // one of every command, with the arguments filled in
+    fn client_messages() -> Vec<ClientMessage> {
+        vec![
+            ClientMessage::Hello(PROTOCOL_VERSION, vec![Capability::Spectate, Capability::Chat]),
+            ClientMessage::Hello(PROTOCOL_VERSION, vec![]),
+            ClientMessage::Join(Team::Purple),
+            ClientMessage::Join(Team::White),
+            ClientMessage::Ready,
+            ClientMessage::Spectate,
+            ClientMessage::Move(Team::White, square(3, 5), square(3, 7)),
+            ClientMessage::Rules(OfferAction::Offer, RuleSet::FORUM),
+            ClientMessage::Rules(OfferAction::Accept, RuleSet::CLASSIC),
+            ClientMessage::Resume("e2e90fc8f412a8a3".to_string()),
+            ClientMessage::RoomList,
+            ClientMessage::RoomCreate("room-1234".to_string()),
+            ClientMessage::RoomJoin("main".to_string()),
+            ClientMessage::RoomLeave,
+            ClientMessage::Chat("good luck, have fun".to_string()),
+            ClientMessage::Resign,
+            ClientMessage::Draw(OfferAction::Offer),
+            ClientMessage::Rematch(OfferAction::Decline),
+            ClientMessage::TimeControl(OfferAction::Offer, Some("300+3".parse().unwrap())),
+            ClientMessage::TimeControl(OfferAction::Decline, None),
+            ClientMessage::Ping(0),
+            ClientMessage::Pong(u64::MAX),
+            ClientMessage::Quit,
+        ]
+    }
+
+    // likewise for everything the server sends
+    fn protocol_messages() -> Vec<ProtocolMessage> {
+        vec![
+            ProtocolMessage::Hello(PROTOCOL_VERSION, vec![Capability::Json]),
+            ProtocolMessage::Error(ProtocolError::InvalidMove, "It isn't your turn".to_string()),
+            ProtocolMessage::TeamJoin(Team::Purple),
+            ProtocolMessage::Move(Team::Purple, square(0, 0), square(6, 0)),
+            ProtocolMessage::Rules(RuleSet::SENTRIES),
+            ProtocolMessage::RoomList(vec![]),
+            ProtocolMessage::RoomList(vec![
+                RoomInfo {
+                    name: "main".to_string(),
+                    players: 2,
+                    spectators: 0,
+                },
+                RoomInfo {
+                    name: "room-42".to_string(),
+                    players: 1,
+                    spectators: 3,
+                },
+            ]),
+            ProtocolMessage::RoomEntered("main".to_string()),
+            ProtocolMessage::RoomLeft("room-42".to_string()),
+            ProtocolMessage::Sync(RuleSet::CLASSIC, vec![]),
+            ProtocolMessage::Sync(
+                RuleSet::FORUM,
+                vec![
+                    (Team::White, square(3, 5), square(3, 7)),
+                    (Team::Purple, square(2, 3), square(1, 3)),
+                ],
+            ),
+            ProtocolMessage::Start,
+            ProtocolMessage::Session("e2e90fc8f412a8a3".to_string()),
+            ProtocolMessage::Resumed("main".to_string(), Team::White),
+            ProtocolMessage::Chat("main".to_string(), "spectator-3".to_string(), "nice move".to_string()),
+            ProtocolMessage::GameOver(WinState::Draw, EndReason::Agreement),
+            ProtocolMessage::GameOver(WinState::PurpleWin, EndReason::Timeout),
+            ProtocolMessage::Draw(OfferAction::Accept, Team::White),
+            ProtocolMessage::Rematch(OfferAction::Offer, Team::Purple),
+            ProtocolMessage::Seat(Team::White),
+            ProtocolMessage::TimeControl(Some("60+0".parse().unwrap())),
+            ProtocolMessage::TimeControl(None),
+            ProtocolMessage::RulesOffer(OfferAction::Offer, Team::Purple, RuleSet::FORUM),
+            ProtocolMessage::TimeControlOffer(OfferAction::Accept, Team::White, Some("180+2".parse().unwrap())),
+            ProtocolMessage::TimeControlOffer(OfferAction::Decline, Team::Purple, None),
+            ProtocolMessage::Clock(299_000, 1, Some(Team::Purple)),
+            ProtocolMessage::Clock(0, 0, None),
+            ProtocolMessage::Ping(7),
+            ProtocolMessage::Pong(7),
+            ProtocolMessage::Board(parse_layout(DEFAULT_LAYOUT).unwrap()),
+        ]
+    }
+
+    #[test]
+    fn client_messages_round_trip() {
+        for msg in client_messages() {
+            let line = msg.to_string();
+            assert_eq!(line.parse::<ClientMessage>(), Ok(msg), "{line}");
+        }
+    }
+
+    #[test]
+    fn protocol_messages_round_trip() {
+        for msg in protocol_messages() {
+            let line = msg.to_string();
+            assert_eq!(line.parse::<ProtocolMessage>(), Ok(msg), "{line}");
+        }
+    }
+
+    #[test]
+    fn malformed_commands_are_errors() {
+        for (line, kind) in [
+            ("", ProtocolError::UnknownCommand),
+            ("   ", ProtocolError::UnknownCommand),
+            ("fly purple", ProtocolError::UnknownCommand),
+            ("MOVE purple (0,0) (0,1)", ProtocolError::UnknownCommand),
+            ("hello", ProtocolError::MissingArg),
+            ("hello three", ProtocolError::IncompatibleVersion),
+            ("join", ProtocolError::MissingArg),
+            ("join green", ProtocolError::InvalidTeam),
+            ("join neutral", ProtocolError::InvalidTeam),
+            ("move purple (0,0)", ProtocolError::MissingArg),
+            ("move purple 0,0 (0,1)", ProtocolError::InvalidMove),
+            ("move purple (0,0) (-1,1)", ProtocolError::InvalidMove),
+            ("move purple (0,0) (99999999999,1)", ProtocolError::InvalidMove),
+            ("rules", ProtocolError::MissingArg),
+            ("rules classic", ProtocolError::MissingArg),
+            ("rules offer chess", ProtocolError::InvalidRules),
+            ("rules maybe classic", ProtocolError::InvalidOffer),
+            ("resume", ProtocolError::MissingArg),
+            ("resume bell\u{7}", ProtocolError::InvalidSession),
+            ("room", ProtocolError::UnknownCommand),
+            ("room join", ProtocolError::MissingArg),
+            ("room join bad/name", ProtocolError::InvalidRoom),
+            ("room create ..", ProtocolError::InvalidRoom),
+            ("chat", ProtocolError::MissingArg),
+            ("chat    ", ProtocolError::MissingArg),
+            ("chat tab\tin\u{7}bell", ProtocolError::InvalidChat),
+            ("draw maybe", ProtocolError::InvalidOffer),
+            ("rematch", ProtocolError::MissingArg),
+            ("timecontrol offer 5", ProtocolError::InvalidRules),
+            ("timecontrol 300+3", ProtocolError::MissingArg),
+            ("timecontrol", ProtocolError::MissingArg),
+            ("ping -1", ProtocolError::UnknownCommand),
+            ("pong", ProtocolError::MissingArg),
+        ] {
+            let err = line.parse::<ClientMessage>().expect_err(line);
+            assert_eq!(err.kind, kind, "{line:?}: {}", err.reason);
+        }
+        let too_long = format!("chat {}", "a".repeat(MAX_CHAT_LEN + 1));
+        assert_eq!(too_long.parse::<ClientMessage>().unwrap_err().kind, ProtocolError::InvalidChat);
+    }
+
+    #[test]
+    fn malformed_messages_are_errors() {
+        for line in [
+            "",
+            "hello",
+            "error",
+            "error NOTANERROR something",
+            "join",
+            "move white (1,1)",
+            "rules backgammon",
+            "rules offer neutral forum",
+            "rules offer purple backgammon",
+            "rooms main",
+            "rooms main:1:x",
+            "rooms main:1:2:3",
+            "room",
+            "left bad/name",
+            "sync classic white (3,5)",
+            "sync classic white (3,5) (3,7) purple",
+            "session",
+            "resumed main",
+            "chat main purple",
+            "chat main bad/sender hello",
+            "gameover purple",
+            "gameover purple boredom",
+            "draw offer",
+            "rematch offer neutral",
+            "seat",
+            "timecontrol 1+",
+            "timecontrol maybe white none",
+            "clock 1",
+            "clock 1 -2",
+            "clock 1 2 green",
+            "ping x",
+            "board 0000000",
+            "board 000000G/0000000/0000000/0000000/0000000/0000000/0000000/0000000/0000000",
+            "unknown",
+        ] {
+            assert!(line.parse::<ProtocolMessage>().is_err(), "{line:?} should not parse");
+        }
+    }
+
+    // a tiny xorshift generator, so the garbage is the same on every run
+    struct Garbage(u64);
+
+    impl Garbage {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn pick<'a>(&mut self, choices: &[&'a str]) -> &'a str {
+            choices[self.next() as usize % choices.len()]
+        }
+
+        // mostly words the parsers know, mixed with bits of anything
+        fn line(&mut self) -> String {
+            const WORDS: &[&str] = &[
+                "hello", "join", "move", "rules", "room", "rooms", "list", "create", "leave", "left",
+                "sync", "session", "resume", "resumed", "chat", "gameover", "draw", "rematch", "seat",
+                "timecontrol", "clock", "ping", "pong", "board", "error", "quit", "offer", "accept",
+                "purple", "white", "neutral", "classic", "forum", "(0,0)", "(6,8)", "(7,9)", "(,)",
+                "(1,", "3", "-1", "18446744073709551616", "300+3", "+", "none", "main", "main:1:2",
+                "::", "UNKNOWN", "INVALIDMOVE", "/", "0800080/43C5961", "\u{0}", "é", "\t", "",
+            ];
+            let words = self.next() % 8;
+            let mut line = String::new();
+            for _ in 0..words {
+                if self.next().is_multiple_of(5) {
+                    let len = self.next() % 6;
+                    let any = |n: u64| char::from_u32((n % 0x3000) as u32).unwrap_or('?');
+                    line.extend((0..len).map(|_| any(self.next())));
+                } else {
+                    line.push_str(self.pick(WORDS));
+                }
+                line.push(if self.next().is_multiple_of(7) { '\t' } else { ' ' });
+            }
+            line
+        }
+    }
+
+    #[test]
+    fn garbage_never_panics() {
+        let mut garbage = Garbage(0x9e37_79b9_7f4a_7c15);
+        for _ in 0..20_000 {
+            let line = garbage.line();
+            // anything that does parse has to survive being written out and read back in
+            if let Ok(msg) = line.parse::<ClientMessage>() {
+                assert_eq!(msg.to_string().parse::<ClientMessage>(), Ok(msg), "{line:?}");
+            }
+            if let Ok(msg) = line.parse::<ProtocolMessage>() {
+                assert_eq!(msg.to_string().parse::<ProtocolMessage>(), Ok(msg), "{line:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_lines_never_panic() {
+        let lines = client_messages()
+            .iter()
+            .map(ToString::to_string)
+            .chain(protocol_messages().iter().map(ToString::to_string))
+            .collect::<Vec<_>>();
+        for line in lines {
+            for (end, _) in line.char_indices() {
+                let _ = line[..end].parse::<ClientMessage>();
+                let _ = line[..end].parse::<ProtocolMessage>();
+            }
+        }
+    }
+
+    #[test]
+    fn both_encodings_agree() {
+        for msg in client_messages() {
+            for encoding in [Encoding::Text, Encoding::Json] {
+                let line = encoding.encode(&msg);
+                assert!(!line.contains('\n'), "{line:?} spans more than one line");
+                assert_eq!(
+                    encoding.decode::<ClientMessage>(&line),
+                    Ok(msg.clone()),
+                    "{line}"
+                );
+            }
+        }
+        for msg in protocol_messages() {
+            for encoding in [Encoding::Text, Encoding::Json] {
+                let line = encoding.encode(&msg);
+                assert!(!line.contains('\n'), "{line:?} spans more than one line");
+                assert_eq!(
+                    encoding.decode::<ProtocolMessage>(&line),
+                    Ok(msg.clone()),
+                    "{line}"
+                );
             }
         }
     }
+
+    // the same bad arguments, written both ways, have to be turned down both ways
+    #[test]
+    fn both_encodings_reject_the_same_commands() {
+        let long_chat = "a".repeat(MAX_CHAT_LEN + 1);
+        for (text, json) in [
+            (
+                "join neutral".to_string(),
+                r#"{"type":"join","args":"neutral"}"#.to_string(),
+            ),
+            (
+                "join green".to_string(),
+                r#"{"type":"join","args":"green"}"#.to_string(),
+            ),
+            (
+                "move neutral (0,0) (0,1)".to_string(),
+                r#"{"type":"move","args":["neutral",[0,0],[0,1]]}"#.to_string(),
+            ),
+            (
+                "rules offer chess".to_string(),
+                r#"{"type":"rules","args":["offer","chess"]}"#.to_string(),
+            ),
+            (
+                "room join bad/name".to_string(),
+                r#"{"type":"room_join","args":"bad/name"}"#.to_string(),
+            ),
+            (
+                "room create".to_string(),
+                r#"{"type":"room_create","args":""}"#.to_string(),
+            ),
+            (
+                format!("chat {long_chat}"),
+                format!(r#"{{"type":"chat","args":"{long_chat}"}}"#),
+            ),
+            (
+                "chat".to_string(),
+                r#"{"type":"chat","args":"   "}"#.to_string(),
+            ),
+            (
+                "draw maybe".to_string(),
+                r#"{"type":"draw","args":"maybe"}"#.to_string(),
+            ),
+            (
+                "timecontrol offer 5".to_string(),
+                r#"{"type":"time_control","args":["offer","5"]}"#.to_string(),
+            ),
+            (
+                "resume".to_string(),
+                r#"{"type":"resume","args":""}"#.to_string(),
+            ),
+            (
+                "resume bell\u{7}".to_string(),
+                r#"{"type":"resume","args":"two words"}"#.to_string(),
+            ),
+            (
+                "ping -1".to_string(),
+                r#"{"type":"ping","args":-1}"#.to_string(),
+            ),
+            ("fly".to_string(), r#"{"type":"fly"}"#.to_string()),
+        ] {
+            assert!(
+                Encoding::Text.decode::<ClientMessage>(&text).is_err(),
+                "{text:?} should not parse"
+            );
+            assert!(
+                Encoding::Json.decode::<ClientMessage>(&json).is_err(),
+                "{json:?} should not parse"
+            );
+        }
+    }
+
+    #[test]
+    fn both_encodings_reject_the_same_messages() {
+        for (text, json) in [
+            ("seat neutral", r#"{"type":"seat","args":"neutral"}"#),
+            (
+                "rules offer neutral forum",
+                r#"{"type":"rules_offer","args":["offer","neutral","forum"]}"#,
+            ),
+            (
+                "clock 1 2 neutral",
+                r#"{"type":"clock","args":[1,2,"neutral"]}"#,
+            ),
+            (
+                "room bad/name",
+                r#"{"type":"room_entered","args":"bad/name"}"#,
+            ),
+            (
+                "chat main bad/sender hi",
+                r#"{"type":"chat","args":["main","bad/sender","hi"]}"#,
+            ),
+            ("session", r#"{"type":"session","args":""}"#),
+            ("session bell\u{7}", r#"{"type":"session","args":"two words"}"#),
+            ("board 0000000", r#"{"type":"board","args":"0000000"}"#),
+            (
+                "gameover purple boredom",
+                r#"{"type":"game_over","args":["purple","boredom"]}"#,
+            ),
+        ] {
+            assert!(
+                Encoding::Text.decode::<ProtocolMessage>(text).is_err(),
+                "{text:?} should not parse"
+            );
+            assert!(
+                Encoding::Json.decode::<ProtocolMessage>(json).is_err(),
+                "{json:?} should not parse"
+            );
+        }
+    }
 }
@@ -0,0 +1,164 @@
+use std::time::{Duration, Instant};
+
+use crate::board::BoardSquareCoords;
+use crate::game::{EndReason, GameState, RuleSet, Team, TimeControl, WinState};
+
+// what every new room starts out with, as configured when guardsd was started
+#[derive(Clone)]
+pub struct RoomDefaults {
+    pub rules: RuleSet,
+    pub time_control: Option<TimeControl>,
+    pub layout: String,
+}
+
+// a single game hosted by the server; which clients are in it is tracked on the clients themselves
+pub struct Room {
+    pub game: GameState,
+    // every move played so far in the current game
+    pub moves: Vec<(Team, BoardSquareCoords, BoardSquareCoords)>,
+    // set once both seats are filled and both players are ready; moves are refused until then
+    pub playing: bool,
+    // how the current game ended, once it has; the board is kept as it was until a rematch
+    pub result: Option<(WinState, EndReason)>,
+    // a draw or rematch proposed by one player, waiting for the other to answer
+    pub draw_offer: Option<Team>,
+    pub rematch_offer: Option<Team>,
+    // likewise for new rules or a new time control, which can only be proposed before the first move
+    pub rules_offer: Option<(Team, RuleSet)>,
+    pub time_control_offer: Option<(Team, Option<TimeControl>)>,
+    // None for untimed games
+    pub time_control: Option<TimeControl>,
+    pub clock: Option<Clock>,
+}
+
+impl Room {
+    pub fn new(defaults: &RoomDefaults) -> Self {
+        let mut game = GameState::new(defaults.rules);
+        game.set_layout(defaults.layout.clone());
+        Self {
+            game,
+            moves: Vec::new(),
+            playing: false,
+            result: None,
+            draw_offer: None,
+            rematch_offer: None,
+            rules_offer: None,
+            time_control_offer: None,
+            time_control: defaults.time_control,
+            clock: defaults.time_control.map(Clock::new),
+        }
+    }
+
+    // the rules can only be renegotiated until the first move has been played
+    pub fn started(&self) -> bool {
+        !self.moves.is_empty()
+    }
+
+    pub fn in_progress(&self) -> bool {
+        self.playing && self.result.is_none()
+    }
+
+    pub fn finish(&mut self, win: WinState, reason: EndReason) {
+        self.game.game_over = Some(win);
+        self.result = Some((win, reason));
+        self.draw_offer = None;
+        self.rematch_offer = None;
+        if let Some(clock) = &mut self.clock {
+            clock.stop(Instant::now());
+        }
+    }
+
+    // a fresh board under the same rules
+    pub fn reset(&mut self) {
+        self.game.reset();
+        self.moves.clear();
+        self.result = None;
+        self.draw_offer = None;
+        self.rematch_offer = None;
+        self.clock = self.time_control.map(Clock::new);
+    }
+
+    pub fn set_time_control(&mut self, time_control: Option<TimeControl>) {
+        self.time_control = time_control;
+        self.clock = time_control.map(Clock::new);
+    }
+}
+
+// both players' remaining time; only the player to move has their time running
+pub struct Clock {
+    increment: Duration,
+    purple: Duration,
+    white: Duration,
+    // whose time is running and since when; None while the game is paused or over
+    running: Option<(Team, Instant)>,
+}
+
+impl Clock {
+    pub fn new(time_control: TimeControl) -> Self {
+        Self {
+            increment: time_control.increment,
+            purple: time_control.base,
+            white: time_control.base,
+            running: None,
+        }
+    }
+
+    fn stored(&mut self, team: Team) -> &mut Duration {
+        match team {
+            Team::Purple => &mut self.purple,
+            Team::White => &mut self.white,
+            Team::Neutral => panic!("Neutral team has no clock, this is a programming error"),
+        }
+    }
+
+    pub fn remaining(&self, team: Team, now: Instant) -> Duration {
+        let stored = match team {
+            Team::Purple => self.purple,
+            Team::White => self.white,
+            Team::Neutral => panic!("Neutral team has no clock, this is a programming error"),
+        };
+        match self.running {
+            Some((running, since)) if running == team => {
+                stored.saturating_sub(now.saturating_duration_since(since))
+            }
+            _ => stored,
+        }
+    }
+
+    pub fn running(&self) -> Option<Team> {
+        self.running.map(|(team, _)| team)
+    }
+
+    pub fn start(&mut self, team: Team, now: Instant) {
+        if self.running.is_none() {
+            self.running = Some((team, now));
+        }
+    }
+
+    pub fn stop(&mut self, now: Instant) {
+        if let Some((team, _)) = self.running {
+            let remaining = self.remaining(team, now);
+            *self.stored(team) = remaining;
+            self.running = None;
+        }
+    }
+
+    // the end of a player's turn: their time stops, they get the increment and their opponent's
+    // time starts
+    pub fn punch(&mut self, team: Team, now: Instant) {
+        self.stop(now);
+        let increment = self.increment;
+        *self.stored(team) += increment;
+        self.running = Some((team.opposite(), now));
+    }
+
+    // whose flag has fallen, if anyone's
+    pub fn flagged(&self, now: Instant) -> Option<Team> {
+        self.running().filter(|team| self.remaining(*team, now).is_zero())
+    }
+
+    // when the running player's time will run out
+    pub fn deadline(&self) -> Option<Instant> {
+        self.running.map(|(team, since)| since + self.remaining(team, since))
+    }
+}
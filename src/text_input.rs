@@ -0,0 +1,65 @@
+use macroquad::prelude::*;
+
+// a single line of free text typed by the player
+pub struct TextInput {
+    pub text: String,
+    // only a focused input takes keyboard input
+    pub focused: bool,
+    max_len: usize,
+}
+
+impl TextInput {
+    pub fn new(max_len: usize) -> Self {
+        Self {
+            text: String::new(),
+            focused: false,
+            max_len,
+        }
+    }
+
+    // feeds this frame's typing into the input; returns what was typed once enter is pressed
+    pub fn update(&mut self) -> Option<String> {
+        if !self.focused {
+            return None;
+        }
+        while let Some(c) = get_char_pressed() {
+            // backspace and enter arrive as characters on some platforms, they're handled below
+            if !c.is_control() && self.text.chars().count() < self.max_len {
+                self.text.push(c);
+            }
+        }
+        if is_key_pressed(KeyCode::Backspace) {
+            self.text.pop();
+        }
+        if is_key_pressed(KeyCode::Enter) || is_key_pressed(KeyCode::KpEnter) {
+            return Some(std::mem::take(&mut self.text));
+        }
+        None
+    }
+
+    pub fn draw(&self, x: f32, y: f32, width: f32, font_size: f32) {
+        let height = font_size * 1.25;
+        draw_rectangle(x, y, width, height, Color::new(0.0, 0.0, 0.0, 0.6));
+        draw_rectangle_lines(x, y, width, height, 2.0, if self.focused { WHITE } else { GRAY });
+        // blinking cursor
+        let cursor = if self.focused && get_time().fract() < 0.5 { "_" } else { "" };
+        // keep the end of the text in view once it's too long for the box
+        let mut shown = self.text.as_str();
+        let padding = font_size / 4.0;
+        while !shown.is_empty()
+            && measure_text(format!("{shown}{cursor}"), None, font_size as u16, 1.0).width
+                > width - padding * 2.0
+        {
+            let mut chars = shown.chars();
+            chars.next();
+            shown = chars.as_str();
+        }
+        draw_text(
+            format!("{shown}{cursor}"),
+            x + padding,
+            y + font_size,
+            font_size,
+            WHITE,
+        );
+    }
+}
@@ -0,0 +1,235 @@
+use std::io;
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+
+use crate::net::{Capability, ClientMessage, ProtocolMessage, PROTOCOL_VERSION};
+
+// a connection to guardsd that the render loop can poll once a frame without blocking
+pub trait Transport: Send {
+    fn send(&mut self, msg: ClientMessage);
+    // Empty when nothing has arrived yet, Disconnected once the connection has gone
+    fn try_recv(&mut self) -> Result<ProtocolMessage, TryRecvError>;
+}
+
+// stands in for a server connection when we don't have one
+pub struct Offline;
+
+impl Transport for Offline {
+    fn send(&mut self, _msg: ClientMessage) {}
+
+    fn try_recv(&mut self) -> Result<ProtocolMessage, TryRecvError> {
+        Err(TryRecvError::Disconnected)
+    }
+}
+
+// every connection starts with the handshake, before anything else is sent. We stick to the text
+// encoding, JSON is there for bots and dashboards
+fn hello() -> ClientMessage {
+    let capabilities = Capability::ALL
+        .into_iter()
+        .filter(|c| *c != Capability::Json)
+        .collect();
+    ClientMessage::Hello(PROTOCOL_VERSION, capabilities)
+}
+
+// where the outcome of connect_in_background arrives
+pub type PendingConnection = Receiver<io::Result<Box<dyn Transport>>>;
+
+// connects off the render thread where we can; the result arrives on the returned receiver
+pub fn connect_in_background(addr: String) -> PendingConnection {
+    let (result_tx, result_rx) = channel();
+    #[cfg(not(target_arch = "wasm32"))]
+    std::thread::spawn(move || {
+        let _ = result_tx.send(connect(&addr));
+    });
+    // browsers open WebSockets in the background anyway, so there's nothing to wait for
+    #[cfg(target_arch = "wasm32")]
+    let _ = result_tx.send(connect(&addr));
+    result_rx
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub use tcp::connect;
+
+#[cfg(not(target_arch = "wasm32"))]
+mod tcp {
+    use std::io::{self, BufRead, Write};
+    use std::net::{Shutdown, TcpStream};
+    use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+    use std::thread;
+
+    use tracing::error;
+
+    use super::{hello, Transport};
+    use crate::net::{ClientMessage, ProtocolMessage};
+
+    // the line protocol over a plain TCP socket, with a thread each for reading and writing
+    struct TcpTransport {
+        stream: TcpStream,
+        outgoing: Sender<ClientMessage>,
+        incoming: Receiver<ProtocolMessage>,
+    }
+
+    impl Transport for TcpTransport {
+        fn send(&mut self, msg: ClientMessage) {
+            // the writer thread only stops once the connection has failed, which try_recv reports
+            if self.outgoing.send(msg).is_err() {
+                error!("Could not send command, the connection has gone");
+            }
+        }
+
+        fn try_recv(&mut self) -> Result<ProtocolMessage, TryRecvError> {
+            self.incoming.try_recv()
+        }
+    }
+
+    impl Drop for TcpTransport {
+        // wakes up the reader thread, which would otherwise wait forever on a server that has
+        // stopped responding. The writing side is closed by the writer thread once it has sent
+        // whatever was queued before we were dropped, such as a quit
+        fn drop(&mut self) {
+            let _ = self.stream.shutdown(Shutdown::Read);
+        }
+    }
+
+    pub fn connect(addr: &str) -> io::Result<Box<dyn Transport>> {
+        let stream = TcpStream::connect(addr)?;
+        let (netrecv_tx, netrecv_rx) = channel::<ProtocolMessage>();
+        let (netsend_tx, netsend_rx) = channel::<ClientMessage>();
+        // queued for the writer thread below, so there's nothing to go wrong yet
+        let _ = netsend_tx.send(hello());
+        let mut sendconn = stream.try_clone()?;
+        let readconn = stream.try_clone()?;
+        thread::spawn(move || {
+            for msg in netsend_rx {
+                if let Err(e) = sendconn.write_all(format!("{msg}\n").as_bytes()) {
+                    error!("Could not send command to server: {e}");
+                    // so that the reader thread stops too and the game hears we've disconnected
+                    let _ = sendconn.shutdown(Shutdown::Both);
+                    return;
+                }
+            }
+            // the transport has been dropped, so there's nothing more to say
+            let _ = sendconn.shutdown(Shutdown::Write);
+        });
+        thread::spawn(move || {
+            let reader = std::io::BufReader::new(readconn);
+            for line in reader.lines() {
+                match line {
+                    Ok(line) if line.trim().is_empty() => {}
+                    Ok(line) => match line.parse::<ProtocolMessage>() {
+                        Ok(msg) => {
+                            if netrecv_tx.send(msg).is_err() {
+                                break;
+                            }
+                        }
+                        Err(e) => {
+                            error!("Server sent malformed message {:?}: {e}", line);
+                        }
+                    },
+                    Err(e) => {
+                        error!("Error reading from stream: {:?}", e);
+                        break;
+                    }
+                }
+            }
+        });
+        Ok(Box::new(TcpTransport {
+            stream,
+            outgoing: netsend_tx,
+            incoming: netrecv_rx,
+        }))
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+pub use websocket::connect;
+
+#[cfg(target_arch = "wasm32")]
+mod websocket {
+    use std::collections::VecDeque;
+    use std::io;
+    use std::sync::mpsc::TryRecvError;
+
+    use tracing::error;
+
+    use super::{hello, Transport};
+    use crate::net::{ClientMessage, ProtocolMessage, PORT, WEBSOCKET_PORT};
+
+    // provided by web/guards_net.js, which wraps the browser's WebSocket
+    extern "C" {
+        fn guards_ws_connect(url: *const u8, len: usize) -> i32;
+        fn guards_ws_send(socket: i32, msg: *const u8, len: usize);
+        // the length of the oldest unread message, -1 if there isn't one yet or -2 once closed
+        fn guards_ws_next_len(socket: i32) -> i32;
+        // copies the oldest unread message into buf and discards it
+        fn guards_ws_recv(socket: i32, buf: *mut u8, len: usize);
+        fn guards_ws_close(socket: i32);
+    }
+
+    // the line protocol over a browser WebSocket, one line per message
+    struct WebSocketTransport {
+        socket: i32,
+        // lines that have arrived but haven't been handed to the game yet
+        pending: VecDeque<String>,
+    }
+
+    impl Transport for WebSocketTransport {
+        fn send(&mut self, msg: ClientMessage) {
+            let line = msg.to_string();
+            unsafe { guards_ws_send(self.socket, line.as_ptr(), line.len()) };
+        }
+
+        fn try_recv(&mut self) -> Result<ProtocolMessage, TryRecvError> {
+            loop {
+                if let Some(line) = self.pending.pop_front() {
+                    match line.parse::<ProtocolMessage>() {
+                        Ok(msg) => return Ok(msg),
+                        Err(e) => {
+                            error!("Server sent malformed message {:?}: {e}", line);
+                            continue;
+                        }
+                    }
+                }
+                let len = unsafe { guards_ws_next_len(self.socket) };
+                match len {
+                    -1 => return Err(TryRecvError::Empty),
+                    len if len < 0 => return Err(TryRecvError::Disconnected),
+                    len => {
+                        let mut buf = vec![0u8; len as usize];
+                        unsafe { guards_ws_recv(self.socket, buf.as_mut_ptr(), buf.len()) };
+                        let text = String::from_utf8_lossy(&buf);
+                        self.pending.extend(
+                            text.lines()
+                                .filter(|line| !line.trim().is_empty())
+                                .map(|line| line.to_string()),
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    impl Drop for WebSocketTransport {
+        fn drop(&mut self) {
+            unsafe { guards_ws_close(self.socket) };
+        }
+    }
+
+    // addr is host:port as typed by the player; the game port maps to guardsd's WebSocket port
+    pub fn connect(addr: &str) -> io::Result<Box<dyn Transport>> {
+        let (host, port) = addr
+            .rsplit_once(':')
+            .and_then(|(host, port)| Some((host, port.parse::<u16>().ok()?)))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Expected host:port"))?;
+        let port = if port == PORT { WEBSOCKET_PORT } else { port };
+        let url = format!("ws://{host}:{port}");
+        let socket = unsafe { guards_ws_connect(url.as_ptr(), url.len()) };
+        let mut transport = WebSocketTransport {
+            socket,
+            pending: VecDeque::new(),
+        };
+        // queued by the browser until the socket opens
+        transport.send(hello());
+        Ok(Box::new(transport))
+    }
+}
@@ -0,0 +1,83 @@
+// WebSocket support for the browser build of Guards!, used by src/transport.rs
+"use strict";
+
+const guards_sockets = [];
+
+function guards_ws_connect(url_ptr, url_len) {
+    const entry = { socket: null, queued: [], received: [], closed: false };
+    try {
+        entry.socket = new WebSocket(UTF8ToString(url_ptr, url_len));
+    } catch (e) {
+        console.error("Could not open WebSocket", e);
+        entry.closed = true;
+        guards_sockets.push(entry);
+        return guards_sockets.length - 1;
+    }
+    entry.socket.onopen = function () {
+        // anything sent while we were still connecting
+        for (const msg of entry.queued) {
+            entry.socket.send(msg);
+        }
+        entry.queued = [];
+    };
+    entry.socket.onmessage = function (event) {
+        if (typeof event.data === "string") {
+            entry.received.push(new TextEncoder().encode(event.data));
+        }
+    };
+    entry.socket.onclose = function () {
+        entry.closed = true;
+    };
+    entry.socket.onerror = function (event) {
+        console.error("WebSocket error", event);
+    };
+    guards_sockets.push(entry);
+    return guards_sockets.length - 1;
+}
+
+function guards_ws_send(id, msg_ptr, msg_len) {
+    const entry = guards_sockets[id];
+    if (entry.closed) {
+        return;
+    }
+    const msg = UTF8ToString(msg_ptr, msg_len);
+    if (entry.socket.readyState === WebSocket.CONNECTING) {
+        entry.queued.push(msg);
+    } else {
+        entry.socket.send(msg);
+    }
+}
+
+function guards_ws_next_len(id) {
+    const entry = guards_sockets[id];
+    if (entry.received.length > 0) {
+        return entry.received[0].length;
+    }
+    return entry.closed ? -2 : -1;
+}
+
+function guards_ws_recv(id, buf_ptr, buf_len) {
+    const msg = guards_sockets[id].received.shift();
+    new Uint8Array(wasm_memory.buffer, buf_ptr, buf_len).set(msg.subarray(0, buf_len));
+}
+
+function guards_ws_close(id) {
+    const entry = guards_sockets[id];
+    entry.closed = true;
+    entry.received = [];
+    if (entry.socket !== null) {
+        entry.socket.close();
+    }
+}
+
+miniquad_add_plugin({
+    register_plugin: function (importObject) {
+        importObject.env.guards_ws_connect = guards_ws_connect;
+        importObject.env.guards_ws_send = guards_ws_send;
+        importObject.env.guards_ws_next_len = guards_ws_next_len;
+        importObject.env.guards_ws_recv = guards_ws_recv;
+        importObject.env.guards_ws_close = guards_ws_close;
+    },
+    name: "guards_net",
+    version: "0.1.0",
+});